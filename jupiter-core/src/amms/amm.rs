@@ -2,3 +2,11 @@ pub use jupiter_amm_interface::{
     try_get_account_data, AccountMap, Amm, KeyedAccount, KeyedUiAccount, Quote, QuoteParams, Side,
     Swap, SwapAndAccountMetas, SwapMode, SwapParams,
 };
+
+use anyhow::Result;
+
+/// Reserves of the pool behind an [`Amm`], in the order of [`Amm::get_reserve_mints`], for
+/// callers treating the supported AMMs uniformly. Kept as of the last [`Amm::update`]
+pub trait ReserveQuery: Amm {
+    fn reserves(&self) -> Result<Vec<u128>>;
+}
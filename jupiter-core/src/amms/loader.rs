@@ -1,12 +1,54 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
 
 use anyhow::{anyhow, Result};
 use jupiter_amm_interface::{Amm, AmmContext, KeyedAccount};
+use lazy_static::lazy_static;
 use solana_sdk::pubkey::Pubkey;
 
 use super::spl_token_swap_amm::{SplTokenSwapAmm, SPL_TOKEN_SWAP_PROGRAMS};
 use super::sega_amm::{SegaAmm, SEGA_SWAP_PROGRAMS};
 
+/// Builds the [`Amm`] of a pool from its account
+pub type AmmConstructor = fn(&KeyedAccount, &AmmContext) -> Result<Box<dyn Amm + Send + Sync>>;
+
+lazy_static! {
+    static ref AMM_CONSTRUCTORS: RwLock<HashMap<Pubkey, AmmConstructor>> = {
+        let mut m = HashMap::new();
+        // Add your AMM here
+        for program_id in SPL_TOKEN_SWAP_PROGRAMS.keys() {
+            m.insert(*program_id, new_amm::<SplTokenSwapAmm> as AmmConstructor);
+        }
+        for program_id in SEGA_SWAP_PROGRAMS.keys() {
+            m.insert(*program_id, new_amm::<SegaAmm> as AmmConstructor);
+        }
+        RwLock::new(m)
+    };
+}
+
+fn new_amm<T: Amm + Send + Sync + 'static>(
+    keyed_account: &KeyedAccount,
+    amm_context: &AmmContext,
+) -> Result<Box<dyn Amm + Send + Sync>> {
+    Ok(Box::new(T::from_keyed_account(keyed_account, amm_context)?))
+}
+
+/// Makes `amm_factory` build pools owned by `program_id` with `amm_constructor`,
+/// replacing any constructor previously registered for it
+pub fn register_amm(program_id: Pubkey, amm_constructor: AmmConstructor) {
+    AMM_CONSTRUCTORS
+        .write()
+        .unwrap()
+        .insert(program_id, amm_constructor);
+}
+
+/// Owners of the pools `amm_factory` can build, the built-in programs and any registered one
+pub fn supported_program_ids() -> Vec<Pubkey> {
+    let mut program_ids: Vec<Pubkey> = AMM_CONSTRUCTORS.read().unwrap().keys().copied().collect();
+    program_ids.sort();
+    program_ids
+}
+
 pub fn amm_factory(
     keyed_account: &KeyedAccount,
     amm_context: &AmmContext,
@@ -14,22 +56,13 @@ pub fn amm_factory(
 ) -> Result<Box<dyn Amm + Send + Sync>> {
     let owner = keyed_account.account.owner;
 
-    // Add your AMM here
-    if SPL_TOKEN_SWAP_PROGRAMS.contains_key(&owner) {
-        Ok(Box::new(SplTokenSwapAmm::from_keyed_account(
-            keyed_account,
-            amm_context,
-        )?))
-    } else if SEGA_SWAP_PROGRAMS.contains_key(&owner) {
-        Ok(Box::new(SegaAmm::from_keyed_account(
-            keyed_account,
-            amm_context,
-        )?))
-    } else {
-        Err(anyhow!(
+    let amm_constructor = AMM_CONSTRUCTORS.read().unwrap().get(&owner).copied();
+    match amm_constructor {
+        Some(amm_constructor) => amm_constructor(keyed_account, amm_context),
+        None => Err(anyhow!(
             "Unsupported pool {}, from owner {}",
             keyed_account.key,
             keyed_account.account.owner
-        ))
+        )),
     }
 }
@@ -1,35 +1,72 @@
 use anyhow::{anyhow, Context, Result};
-use anchor_lang::{AccountDeserialize, ToAccountMetas};
+use anchor_lang::{AccountDeserialize, AccountSerialize, Discriminator, ToAccountMetas};
 use jupiter_amm_interface::{
-    try_get_account_data, AccountMap, Amm, AmmContext, KeyedAccount, Quote, QuoteParams,
-    SwapAndAccountMetas, SwapParams, Swap,
+    try_get_account_data, AccountMap, Amm, AmmContext, AmmUserSetup, ClockRef, KeyedAccount,
+    Quote, QuoteParams, SwapAndAccountMetas, SwapMode, SwapParams, Swap,
 };
-use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use spl_token_2022::extension::BaseStateWithExtensions;
 use spl_token_2022::extension::{
-    transfer_fee::TransferFeeConfig, StateWithExtensions, StateWithExtensionsOwned,
+    default_account_state::DefaultAccountState,
+    interest_bearing_mint::InterestBearingConfig,
+    memo_transfer::memo_required,
+    non_transferable::NonTransferable,
+    permanent_delegate::PermanentDelegate,
+    transfer_fee::{TransferFeeConfig, MAX_FEE_BASIS_POINTS},
+    transfer_hook::TransferHook,
+    AccountType, StateWithExtensions, StateWithExtensionsOwned,
 };
 use lazy_static::lazy_static;
-use spl_token_2022::state::Mint;
+use spl_token_2022::state::{AccountState, Mint};
+use spl_tlv_account_resolution::{account::ExtraAccountMeta, state::ExtraAccountMetaList};
+use spl_transfer_hook_interface::{
+    get_extra_account_metas_address,
+    instruction::{ExecuteInstruction, TransferHookInstruction},
+};
+use spl_type_length_value::state::TlvStateBorrowed;
 use std::sync::atomic::{AtomicI64, AtomicU64};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
-use solana_sdk::{pubkey, pubkey::Pubkey};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    program_pack::Pack,
+    pubkey,
+    pubkey::Pubkey,
+};
 
 use sega_cp_swap::{
-    AmmConfig, PoolState, AUTH_SEED, ObservationState, CurveCalculator, SegaSwap,
-    PoolStatusBitIndex
+    AmmConfig, PoolState, AUTH_SEED, ObservationState, CurveCalculator, CurveType, SegaSwap,
+    PoolStatusBitIndex, Q32, FeeBreakdown, RoundDirection, TradingTokenResult, U128,
+    FEE_RATE_DENOMINATOR_VALUE,
 };
 
+use super::amm::ReserveQuery;
+
 mod sega_swap_programs {
     use super::*;
     pub const SEGA: Pubkey = pubkey!("SegaXNnoXYTZiqUt9Xn2XqGcL56b25yzXLuJSpadcMu");
+    // The devnet deployment address is supplied at build time
+    #[cfg(feature = "devnet")]
+    pub const SEGA_DEVNET: &str = env!("SEGA_DEVNET_PROGRAM_ID");
 }
 
+// Liquidity the program keeps out of the first depositor's LP tokens
+const LOCK_LP_AMOUNT: u64 = 100;
+
 lazy_static! {
     pub static ref SEGA_SWAP_PROGRAMS: HashMap<Pubkey, String> = {
         let mut m = HashMap::new();
         m.insert(sega_swap_programs::SEGA, "Sega".into());
+        #[cfg(feature = "devnet")]
+        m.insert(
+            sega_swap_programs::SEGA_DEVNET
+                .parse()
+                .expect("Invalid SEGA_DEVNET_PROGRAM_ID"),
+            "Sega Devnet".into(),
+        );
         m
     };
 }
@@ -42,314 +79,5688 @@ pub struct TokenMints {
     token1_mint: StateWithExtensionsOwned<Mint>,
     token0_program: Pubkey,
     token1_program: Pubkey,
+    token0_transfer_hook_program: Option<Pubkey>,
+    token1_transfer_hook_program: Option<Pubkey>,
+    // Extra accounts listed by the validation account of each hook, `None` until it is loaded
+    token0_transfer_hook_extra_accounts: Option<Vec<ExtraAccountMeta>>,
+    token1_transfer_hook_extra_accounts: Option<Vec<ExtraAccountMeta>>,
+    // Looked up once per update, `None` being definitive for mints without the extension,
+    // so quoting never parses the mint extensions again
+    token0_transfer_fee_config: Option<TransferFeeConfig>,
+    token1_transfer_fee_config: Option<TransferFeeConfig>,
+    token0_default_frozen: bool,
+    token1_default_frozen: bool,
+    token0_scaled_ui_amount: Option<ScaledUiAmountConfig>,
+    token1_scaled_ui_amount: Option<ScaledUiAmountConfig>,
+    token0_permanent_delegate: bool,
+    token1_permanent_delegate: bool,
+    non_transferable: bool,
+}
+
+impl TokenMints {
+    fn new(
+        pool_state: &PoolState,
+        token0_mint: StateWithExtensionsOwned<Mint>,
+        token1_mint: StateWithExtensionsOwned<Mint>,
+    ) -> Self {
+        Self {
+            token0: pool_state.token_0_mint,
+            token1: pool_state.token_1_mint,
+            token0_transfer_hook_program: transfer_hook_program_id(&token0_mint),
+            token1_transfer_hook_program: transfer_hook_program_id(&token1_mint),
+            token0_transfer_hook_extra_accounts: None,
+            token1_transfer_hook_extra_accounts: None,
+            token0_transfer_fee_config: transfer_fee_config(&token0_mint),
+            token1_transfer_fee_config: transfer_fee_config(&token1_mint),
+            token0_default_frozen: default_frozen(&token0_mint),
+            token1_default_frozen: default_frozen(&token1_mint),
+            token0_scaled_ui_amount: scaled_ui_amount_config(&token0_mint),
+            token1_scaled_ui_amount: scaled_ui_amount_config(&token1_mint),
+            token0_permanent_delegate: permanent_delegate(&token0_mint),
+            token1_permanent_delegate: permanent_delegate(&token1_mint),
+            non_transferable: token0_mint.get_extension::<NonTransferable>().is_ok()
+                || token1_mint.get_extension::<NonTransferable>().is_ok(),
+            token0_mint,
+            token1_mint,
+            token0_program: pool_state.token_0_program,
+            token1_program: pool_state.token_1_program,
+        }
+    }
+
+    /// The mint, its hook program and the validation account of that program, for each mint
+    /// carrying a transfer hook
+    fn transfer_hooks(&self) -> impl Iterator<Item = (Pubkey, Pubkey, Pubkey)> {
+        [
+            (self.token0, self.token0_transfer_hook_program),
+            (self.token1, self.token1_transfer_hook_program),
+        ]
+        .into_iter()
+        .filter_map(|(mint, program_id)| {
+            let program_id = program_id?;
+            Some((
+                mint,
+                program_id,
+                get_extra_account_metas_address(&mint, &program_id),
+            ))
+        })
+    }
+
+    fn transfer_hook_extra_accounts(&self, mint: &Pubkey) -> Option<&[ExtraAccountMeta]> {
+        if *mint == self.token0 {
+            self.token0_transfer_hook_extra_accounts.as_deref()
+        } else {
+            self.token1_transfer_hook_extra_accounts.as_deref()
+        }
+    }
+
+    /// Number of accounts [`TokenMints::transfer_hook_account_metas`] returns for `mint`,
+    /// counting none for extra accounts not loaded yet
+    fn transfer_hook_accounts_len(&self, mint: &Pubkey) -> usize {
+        let program_id = if *mint == self.token0 {
+            self.token0_transfer_hook_program
+        } else {
+            self.token1_transfer_hook_program
+        };
+        program_id.map_or(0, |_| {
+            2 + self
+                .transfer_hook_extra_accounts(mint)
+                .map_or(0, <[_]>::len)
+        })
+    }
+
+    /// The hook program of `mint`, its validation account and the extra accounts listed there,
+    /// resolved for the execute instruction of a transfer of `amount` from `source` to `destination`
+    /// signed by `authority`. Nothing for a mint without a hook.
+    /// Extra accounts derived from account data cannot be resolved, the swap accounts are not fetched
+    fn transfer_hook_account_metas(
+        &self,
+        mint: Pubkey,
+        source: Pubkey,
+        destination: Pubkey,
+        authority: Pubkey,
+        amount: u64,
+    ) -> Result<Vec<AccountMeta>> {
+        let Some((_, program_id, validation_account)) = self
+            .transfer_hooks()
+            .find(|(hook_mint, _, _)| *hook_mint == mint)
+        else {
+            return Ok(Vec::new());
+        };
+        let extra_account_metas = self.transfer_hook_extra_accounts(&mint).with_context(|| {
+            format!("Transfer hook validation account of mint {} missing", mint)
+        })?;
+
+        let instruction_data = TransferHookInstruction::Execute { amount }.pack();
+        // The seeds of the extra accounts index into those of the execute instruction,
+        // each resolved extra account included
+        let mut execute_accounts = vec![source, mint, destination, authority, validation_account];
+        let mut account_metas = vec![
+            AccountMeta::new_readonly(program_id, false),
+            AccountMeta::new_readonly(validation_account, false),
+        ];
+        for (index, extra_account_meta) in extra_account_metas.iter().enumerate() {
+            let account_meta = extra_account_meta
+                .resolve(&instruction_data, &program_id, |account_index| {
+                    execute_accounts.get(account_index).map(|key| (key, None))
+                })
+                .with_context(|| {
+                    format!(
+                        "Cannot resolve extra account {} of the transfer hook of mint {}",
+                        index, mint
+                    )
+                })?;
+            execute_accounts.push(account_meta.pubkey);
+            account_metas.push(account_meta);
+        }
+        Ok(account_metas)
+    }
+}
+
+// The decoded mints are summarized by their extension types
+impl std::fmt::Debug for TokenMints {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenMints")
+            .field("token0", &self.token0)
+            .field("token0_program", &self.token0_program)
+            .field(
+                "token0_extensions",
+                &self.token0_mint.get_extension_types().unwrap_or_default(),
+            )
+            .field("token1", &self.token1)
+            .field("token1_program", &self.token1_program)
+            .field(
+                "token1_extensions",
+                &self.token1_mint.get_extension_types().unwrap_or_default(),
+            )
+            .finish()
+    }
+}
+
+/// A [`Quote`] along with the Sega specific details the interface has no room for
+#[derive(Clone, Debug, Default)]
+pub struct SegaQuote {
+    pub quote: Quote,
+    /// Output of the curve, before the destination transfer fee brings it down to `quote.out_amount`
+    pub gross_out_amount: u64,
+    /// Relative gap between the pre-trade spot price and the execution price, as a fraction
+    pub price_impact_pct: Decimal,
+    /// `quote.fee_amount` split between liquidity providers, protocol and fund
+    pub fee_breakdown: FeeBreakdown,
+    /// Whether the quote is for less than the requested amount, see [`SegaAmm::set_partial_fill`]
+    pub partial_fill: bool,
+}
+
+/// LP tokens minted by a deposit and the token amounts it takes, transfer fees included
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DepositQuote {
+    pub lp_amount: u64,
+    pub amount_0: u64,
+    pub amount_1: u64,
+}
+
+/// Operations the pool status bits leave enabled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolStatus {
+    pub swap_enabled: bool,
+    pub deposit_enabled: bool,
+    pub withdraw_enabled: bool,
+}
+
+/// Lifecycle and fee accounting fields of the pool state, with the status bits decoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolInfo {
+    pub amm_config: Pubkey,
+    pub pool_creator: Pubkey,
+    pub lp_mint: Pubkey,
+    /// Unix timestamp from which swaps are allowed
+    pub open_time: u64,
+    pub recent_epoch: u64,
+    pub auth_bump: u8,
+    pub deposit_enabled: bool,
+    pub withdraw_enabled: bool,
+    pub swap_enabled: bool,
+    pub lp_mint_decimals: u8,
+    pub mint_0_decimals: u8,
+    pub mint_1_decimals: u8,
+    pub lp_supply: u64,
+    pub protocol_fees_token_0: u64,
+    pub protocol_fees_token_1: u64,
+    pub fund_fees_token_0: u64,
+    pub fund_fees_token_1: u64,
+}
+
+/// Round trip buying the other mint of the pair with `in_amount` of `input_mint` in `buy_pool`,
+/// then selling it back for `out_amount` of `input_mint` in `sell_pool`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArbOpportunity {
+    pub buy_pool: Pubkey,
+    pub sell_pool: Pubkey,
+    pub input_mint: Pubkey,
+    pub in_amount: u64,
+    pub out_amount: u64,
+}
+
+impl ArbOpportunity {
+    /// Zero when the round trip returns less than it spent
+    pub fn profit(&self) -> u64 {
+        self.out_amount.saturating_sub(self.in_amount)
+    }
+}
+
+/// What `update` found in a pool vault account
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VaultState {
+    Amount(u64),
+    Frozen,
+    #[default]
+    Missing,
+    /// The account is a token account of a mint other than the pool one
+    WrongMint,
+}
+
+impl VaultState {
+    fn amount(self, vault: u8) -> std::result::Result<u64, SegaQuoteError> {
+        match self {
+            VaultState::Amount(amount) => Ok(amount),
+            VaultState::Frozen => Err(SegaQuoteError::VaultFrozen {
+                vault,
+                by_default_account_state: false,
+            }),
+            VaultState::Missing => Err(SegaQuoteError::VaultMissing { vault }),
+            VaultState::WrongMint => Err(SegaQuoteError::VaultMintMismatch { vault }),
+        }
+    }
+}
+
+/// Quote failures to branch on, the returned [`anyhow::Error`] can be downcast to them.
+/// Other failures are plain errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegaQuoteError {
+    /// Swaps are disabled, or the pool is not open yet
+    NotTrading,
+    /// The pool has an `open_time` but the clock timestamp was never set, see [`Amm::from_keyed_account`]
+    ClockUninitialized,
+    /// Nothing is left to swap once the transfer fee is taken, or the exact out amount is so
+    /// small that the curve requires no input for it
+    AmountTooLow,
+    /// Vault 0 or 1 is frozen, by the default account state of its mint or by its freeze authority
+    VaultFrozen {
+        vault: u8,
+        by_default_account_state: bool,
+    },
+    /// Vault 0 or 1 was not found by the last update
+    VaultMissing { vault: u8 },
+    /// Vault 0 or 1 holds a mint other than token 0 or token 1 of the pool
+    VaultMintMismatch { vault: u8 },
+    /// One side of the pool holds nothing to price the swap against
+    EmptyReserve,
+    /// The price impact is above the limit set with [`SegaAmm::set_max_price_impact_bps`]
+    PriceImpactTooHigh,
+    /// The exact out amount grossed up by the destination transfer fee is at least the reserve
+    OutputUnreachable,
+    /// The curve cannot fill the swap from the current reserves, or its fees overflow
+    CurveFailure,
+    /// An amount the curve came up with does not fit in a u64
+    AmountOverflow,
+}
+
+impl std::fmt::Display for SegaQuoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SegaQuoteError::NotTrading => write!(f, "Pool is not trading"),
+            SegaQuoteError::ClockUninitialized => write!(f, "Clock not initialized"),
+            SegaQuoteError::AmountTooLow => write!(f, "Amount too low"),
+            SegaQuoteError::VaultFrozen {
+                vault,
+                by_default_account_state: false,
+            } => write!(f, "Vault {vault} frozen"),
+            SegaQuoteError::VaultFrozen {
+                vault,
+                by_default_account_state: true,
+            } => write!(
+                f,
+                "Vault {vault} frozen, its mint freezes new token accounts by default"
+            ),
+            SegaQuoteError::VaultMissing { vault } => write!(f, "Vault {vault} missing"),
+            SegaQuoteError::VaultMintMismatch { vault } => {
+                write!(f, "Vault {vault} does not hold the pool mint")
+            }
+            SegaQuoteError::EmptyReserve => write!(f, "Pool has empty reserve"),
+            SegaQuoteError::PriceImpactTooHigh => write!(f, "Price impact too high"),
+            SegaQuoteError::OutputUnreachable => write!(f, "Target output unreachable"),
+            SegaQuoteError::CurveFailure => write!(f, "Swap failed"),
+            SegaQuoteError::AmountOverflow => write!(f, "Amount overflow"),
+        }
+    }
+}
+
+impl std::error::Error for SegaQuoteError {}
+
+/// Decoded state of a [`SegaAmm`], to restore it in another process without fetching its accounts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SegaAmmSnapshot {
+    pub key: Pubkey,
+    pub program_id: Pubkey,
+    /// Serialized pool state account, discriminator included
+    pub pool_state: Vec<u8>,
+    /// Serialized amm config account, discriminator included
+    pub amm_config: Option<Vec<u8>>,
+    pub vault_0: VaultState,
+    pub vault_1: VaultState,
+    /// Token 0 and token 1 mint accounts, extensions included
+    pub token_mints: Option<(Vec<u8>, Vec<u8>)>,
+    /// Serialized observation state account, discriminator included
+    pub observation_state: Option<Vec<u8>>,
+    #[serde(default)]
+    pub vaults_require_memo: (bool, bool),
+    /// Token 0 and token 1 transfer hook validation accounts, as far as the last update loaded them
+    #[serde(default)]
+    pub transfer_hook_extra_account_metas: (Option<Vec<u8>>, Option<Vec<u8>>),
+}
+
+/// Notified of every quote a [`SegaAmm`] computes, see [`SegaAmm::set_quote_observer`]
+pub trait QuoteObserver: Send + Sync {
+    fn on_quote_success(&self, elapsed: std::time::Duration);
+    /// `error` is None for failures outside of [`SegaQuoteError`], such as mints not in the pool
+    fn on_quote_failure(&self, elapsed: std::time::Duration, error: Option<SegaQuoteError>);
 }
 
+/// Decoded amm configs by address, along with the data they were decoded from. Pools sharing a
+/// config then decode it once per change when they are given the same cache
+pub type AmmConfigCache = Arc<RwLock<HashMap<Pubkey, (Vec<u8>, AmmConfig)>>>;
+
+/// A Sega constant product pool. It is `Send + Sync` and quoting only takes `&self`, the clock
+/// being atomics, so a single instance behind an `Arc` can serve quotes to several threads while
+/// one holding it mutably is the only one to [`Amm::update`] it
 #[derive(Clone)]
 pub struct SegaAmm {
     key: Pubkey,
     pool_state: PoolState,
     amm_config: Option<AmmConfig>,
-    vault_0_amount: Option<u64>,
-    vault_1_amount: Option<u64>,
-    token_mints_and_token_programs: Option<TokenMints>,
+    vault_0: VaultState,
+    vault_1: VaultState,
+    // The decoded accounts that `update` replaces wholesale are shared between clones, which then
+    // only bump reference counts instead of copying the 4 KB observation ring buffer and both
+    // mints along with their extension data
+    token_mints_and_token_programs: Option<Arc<TokenMints>>,
+    observation_state: Option<Arc<ObservationState>>,
+    authority: Pubkey,
+    accounting_inconsistent: bool,
+    vaults_require_memo: (bool, bool),
+    quote_before_open_time: bool,
+    partial_fill: bool,
+    max_price_impact_bps: Option<u16>,
+    quote_observer: Option<Arc<dyn QuoteObserver>>,
+    amm_config_cache: Option<AmmConfigCache>,
+    // Hash of the accounts the last successful update read
+    update_fingerprint: Option<u64>,
+    // Shared with the `ClockRef` of the `AmmContext`, the caller advances it as the chain does.
+    // `update` never reads the clock, so a lagging epoch picks the wrong transfer fee tier
     epoch: Arc<AtomicU64>,
     timestamp: Arc<AtomicI64>,
     program_id: Pubkey,
 }
 
+// Sharing across threads is part of the API, a field breaking it should fail to build here
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SegaAmm>();
+};
+
 impl SegaAmm {
-    fn get_authority(&self) -> Pubkey {
-        Pubkey::create_program_address(
-            &[AUTH_SEED.as_bytes(), &[self.pool_state.auth_bump]],
-            &self.program_id,
-        )
-        .unwrap()
+    /// Builds a ready to quote instance from already decoded accounts, without going through
+    /// [`Amm::from_keyed_account`] and [`Amm::update`]
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_parts(
+        key: Pubkey,
+        program_id: Pubkey,
+        pool_state: PoolState,
+        amm_config: AmmConfig,
+        vault_0_amount: u64,
+        vault_1_amount: u64,
+        token_0_mint: StateWithExtensionsOwned<Mint>,
+        token_1_mint: StateWithExtensionsOwned<Mint>,
+        epoch: u64,
+        timestamp: i64,
+    ) -> Result<Self> {
+        let vault_0 = VaultState::Amount(vault_0_amount);
+        let vault_1 = VaultState::Amount(vault_1_amount);
+        Ok(Self {
+            key,
+            authority: derive_authority(pool_state.auth_bump, &program_id)?,
+            accounting_inconsistent: accrued_fees_exceed_vaults(&pool_state, vault_0, vault_1),
+            vaults_require_memo: (false, false),
+            quote_before_open_time: false,
+            partial_fill: false,
+            max_price_impact_bps: None,
+            quote_observer: None,
+            amm_config_cache: None,
+            update_fingerprint: None,
+            amm_config: Some(amm_config),
+            vault_0,
+            vault_1,
+            token_mints_and_token_programs: Some(Arc::new(TokenMints::new(
+                &pool_state,
+                token_0_mint,
+                token_1_mint,
+            ))),
+            pool_state,
+            observation_state: None,
+            epoch: Arc::new(AtomicU64::new(epoch)),
+            timestamp: Arc::new(AtomicI64::new(timestamp)),
+            program_id,
+        })
     }
-   
-}
 
-impl Amm for SegaAmm {
-    fn from_keyed_account(keyed_account: &KeyedAccount, amm_context: &AmmContext) -> Result<Self> {
-        let pool_state = PoolState::try_deserialize(&mut keyed_account.account.data.as_ref())?;
+    /// Same as [`Amm::from_keyed_account`], with a clock of its own set at `epoch` and
+    /// `unix_timestamp` rather than the one shared through an `AmmContext`
+    pub fn from_keyed_account_with_clock(
+        keyed_account: &KeyedAccount,
+        epoch: u64,
+        unix_timestamp: i64,
+    ) -> Result<Self> {
+        let clock_ref = ClockRef::default();
+        clock_ref
+            .epoch
+            .store(epoch, std::sync::atomic::Ordering::Relaxed);
+        clock_ref
+            .unix_timestamp
+            .store(unix_timestamp, std::sync::atomic::Ordering::Relaxed);
+        Self::from_keyed_account(keyed_account, &AmmContext { clock_ref })
+    }
+
+    /// Captures what [`Amm::update`] loaded, to be restored with [`SegaAmm::from_snapshot`]
+    pub fn snapshot(&self) -> Result<SegaAmmSnapshot> {
+        let mut pool_state = Vec::new();
+        self.pool_state.try_serialize(&mut pool_state)?;
+        let amm_config = match &self.amm_config {
+            Some(amm_config) => {
+                let mut amm_config_data = Vec::new();
+                amm_config.try_serialize(&mut amm_config_data)?;
+                Some(amm_config_data)
+            }
+            None => None,
+        };
+        let token_mints = self.token_mints_and_token_programs.as_deref();
+        let transfer_hook_extra_account_metas = match token_mints {
+            Some(token_mints) => (
+                token_mints
+                    .token0_transfer_hook_extra_accounts
+                    .as_deref()
+                    .map(extra_account_metas_data)
+                    .transpose()?,
+                token_mints
+                    .token1_transfer_hook_extra_accounts
+                    .as_deref()
+                    .map(extra_account_metas_data)
+                    .transpose()?,
+            ),
+            None => (None, None),
+        };
+
+        Ok(SegaAmmSnapshot {
+            key: self.key,
+            program_id: self.program_id,
+            pool_state,
+            amm_config,
+            vault_0: self.vault_0,
+            vault_1: self.vault_1,
+            token_mints: token_mints.map(|token_mints| {
+                (
+                    mint_data(&token_mints.token0_mint),
+                    mint_data(&token_mints.token1_mint),
+                )
+            }),
+            observation_state: self
+                .observation_state
+                .as_deref()
+                .map(observation_state_data),
+            vaults_require_memo: self.vaults_require_memo,
+            transfer_hook_extra_account_metas,
+        })
+    }
+
+    /// Restores a [`SegaAmm::snapshot`], bound to the clock of `amm_context`
+    pub fn from_snapshot(snapshot: &SegaAmmSnapshot, amm_context: &AmmContext) -> Result<Self> {
+        let pool_state = PoolState::try_deserialize(&mut snapshot.pool_state.as_ref())?;
+        let amm_config = match &snapshot.amm_config {
+            Some(amm_config_data) => {
+                Some(AmmConfig::try_deserialize(&mut amm_config_data.as_ref())?)
+            }
+            None => None,
+        };
+        let token_mints_and_token_programs = match &snapshot.token_mints {
+            Some((token0_mint_data, token1_mint_data)) => {
+                let mut token_mints = TokenMints::new(
+                    &pool_state,
+                    StateWithExtensionsOwned::<Mint>::unpack(token0_mint_data.clone())?,
+                    StateWithExtensionsOwned::<Mint>::unpack(token1_mint_data.clone())?,
+                );
+                let (token0_extra_account_metas, token1_extra_account_metas) =
+                    &snapshot.transfer_hook_extra_account_metas;
+                token_mints.token0_transfer_hook_extra_accounts = token0_extra_account_metas
+                    .as_deref()
+                    .map(decode_extra_account_metas)
+                    .transpose()?;
+                token_mints.token1_transfer_hook_extra_accounts = token1_extra_account_metas
+                    .as_deref()
+                    .map(decode_extra_account_metas)
+                    .transpose()?;
+                Some(Arc::new(token_mints))
+            }
+            None => None,
+        };
+        let observation_state = snapshot
+            .observation_state
+            .as_deref()
+            .map(decode_observation)
+            .transpose()?
+            .map(Arc::new);
 
         Ok(Self {
-            key: keyed_account.key,
+            key: snapshot.key,
+            authority: derive_authority(pool_state.auth_bump, &snapshot.program_id)?,
+            accounting_inconsistent: accrued_fees_exceed_vaults(
+                &pool_state,
+                snapshot.vault_0,
+                snapshot.vault_1,
+            ),
+            vaults_require_memo: snapshot.vaults_require_memo,
+            quote_before_open_time: false,
+            partial_fill: false,
+            max_price_impact_bps: None,
+            quote_observer: None,
+            amm_config_cache: None,
+            update_fingerprint: None,
             pool_state,
-            amm_config: None,
-            vault_0_amount: None,
-            vault_1_amount: None,
-            token_mints_and_token_programs: None,
+            amm_config,
+            vault_0: snapshot.vault_0,
+            vault_1: snapshot.vault_1,
+            token_mints_and_token_programs,
+            observation_state,
             epoch: amm_context.clock_ref.epoch.clone(),
             timestamp: amm_context.clock_ref.unix_timestamp.clone(),
-            program_id: keyed_account.account.owner,
+            program_id: snapshot.program_id,
         })
     }
 
-    fn label(&self) -> String {
-        "SEGA".into()
+    fn get_authority(&self) -> Pubkey {
+        self.authority
     }
 
-    fn program_id(&self) -> Pubkey {
-        self.program_id
+    /// Whether the last update found the pool recording more protocol and fund fees than a vault
+    /// holds. Quoting such a pool fails, as its on chain accounting cannot be trusted
+    pub fn accounting_inconsistent(&self) -> bool {
+        self.accounting_inconsistent
     }
 
-    fn key(&self) -> Pubkey {
-        self.key
+    /// Whether the token 0 and token 1 vaults require memos on incoming transfers, in which case the
+    /// swap has to be preceded by a memo instruction. `MemoTransfer` is a token account extension,
+    /// so the caller's own destination account may require one as well
+    pub fn requires_memo(&self) -> (bool, bool) {
+        self.vaults_require_memo
     }
 
-    fn get_reserve_mints(&self) -> Vec<Pubkey> {
-        vec![self.pool_state.token_0_mint, self.pool_state.token_1_mint]
+    /// Whether the token 0 and token 1 mints have a permanent delegate, an authority able to move
+    /// tokens out of any account, the pool vaults included. Quotes are unaffected, but routers
+    /// may rather avoid such pools. False until [`Amm::update`] loads the mints
+    pub fn has_permanent_delegate(&self) -> (bool, bool) {
+        self.token_mints_and_token_programs
+            .as_deref()
+            .map_or((false, false), |token_mints| {
+                (
+                    token_mints.token0_permanent_delegate,
+                    token_mints.token1_permanent_delegate,
+                )
+            })
     }
 
-    fn get_accounts_to_update(&self) -> Vec<Pubkey> {
-        let mut keys = vec![
-            self.key,
-            self.pool_state.token_0_vault,
-            self.pool_state.token_1_vault,
-            self.pool_state.amm_config,
-        ];
-        keys.extend([self.pool_state.token_0_mint, self.pool_state.token_1_mint]);
-        keys
+    /// Token 0 and token 1 vault balances net of the accrued protocol and fund fees, as used by the quote.
+    /// The fees keep accruing on chain, [`Amm::update`] refreshes them along with the vaults
+    pub fn tradable_reserves(&self) -> Result<(u64, u64)> {
+        // A mint freezing new accounts by default leaves its vault frozen until the freeze
+        // authority thaws it, rather than after an individual freeze
+        let (token0_default_frozen, token1_default_frozen) = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .map_or((false, false), |token_mints| {
+                (
+                    token_mints.token0_default_frozen,
+                    token_mints.token1_default_frozen,
+                )
+            });
+        let vault_amount = |vault_state: VaultState, vault, default_frozen| match vault_state {
+            VaultState::Frozen => Err(SegaQuoteError::VaultFrozen {
+                vault,
+                by_default_account_state: default_frozen,
+            }),
+            vault_state => vault_state.amount(vault),
+        };
+
+        match vault_amount_without_fee(
+            &self.pool_state,
+            vault_amount(self.vault_0, 0, token0_default_frozen)?,
+            vault_amount(self.vault_1, 1, token1_default_frozen)?,
+        ) {
+            (Some(vault_0), Some(vault_1)) => Ok((vault_0, vault_1)),
+            _ => Err(anyhow!("Vault amount underflow")),
+        }
     }
 
-    fn update(&mut self, account_map: &AccountMap) -> Result<()> {
-        let pool_state_data = try_get_account_data(account_map, &self.key)?;
-        self.pool_state = PoolState::try_deserialize(&mut pool_state_data.as_ref())?;
+    /// Signed change of the token 0 and token 1 net reserves, as in [`SegaAmm::tradable_reserves`],
+    /// since `previous`, an earlier state of the same pool. A vault which balance is unknown counts as empty
+    pub fn reserves_delta(&self, previous: &SegaAmm) -> (i128, i128) {
+        let net_reserves = |amm: &SegaAmm| {
+            let (vault_0, vault_1) = vault_amount_without_fee(
+                &amm.pool_state,
+                amm.vault_0.amount(0).unwrap_or(0),
+                amm.vault_1.amount(1).unwrap_or(0),
+            );
+            (vault_0.unwrap_or(0), vault_1.unwrap_or(0))
+        };
+        let (token_0_amount, token_1_amount) = net_reserves(self);
+        let (previous_token_0_amount, previous_token_1_amount) = net_reserves(previous);
+        (
+            i128::from(token_0_amount) - i128::from(previous_token_0_amount),
+            i128::from(token_1_amount) - i128::from(previous_token_1_amount),
+        )
+    }
 
-        let token0_mint = try_get_account_data(account_map, &self.pool_state.token_0_mint)
-            .ok()
-            .and_then(|account_data| {
-                StateWithExtensionsOwned::<spl_token_2022::state::Mint>::unpack(
-                    account_data.to_vec(),
-                )
+    /// Token program of token 0 and token 1, either classic SPL Token or token-2022
+    pub fn token_programs(&self) -> Result<(Pubkey, Pubkey)> {
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .context("Missing token mints and token programs")?;
+        Ok((token_mints.token0_program, token_mints.token1_program))
+    }
+
+    /// `InterestBearingConfig` of token 0 and token 1, to convert raw amounts such as the reserves
+    /// to the UI amounts of interest bearing mints. Balances, and so the swap math, stay in raw
+    /// amounts, only their display depends on the accrued interest
+    pub fn interest_bearing_configs(
+        &self,
+    ) -> Result<(Option<InterestBearingConfig>, Option<InterestBearingConfig>)> {
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .context("Missing token mints and token programs")?;
+        Ok((
+            token_mints
+                .token0_mint
+                .get_extension::<InterestBearingConfig>()
+                .ok()
+                .copied(),
+            token_mints
+                .token1_mint
+                .get_extension::<InterestBearingConfig>()
                 .ok()
+                .copied(),
+        ))
+    }
+
+    /// Multipliers of the token-2022 `ScaledUiAmount` extension of token 0 and token 1 at the clock
+    /// timestamp, 1 for mints without it. Only the UI helpers apply them, the quotes stay in raw amounts
+    pub fn ui_amount_multipliers(&self) -> Result<(Decimal, Decimal)> {
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .context("Missing token mints and token programs")?;
+        let unix_timestamp = self.timestamp.load(std::sync::atomic::Ordering::Relaxed);
+        let multiplier = |scaled_ui_amount: Option<ScaledUiAmountConfig>| {
+            scaled_ui_amount.map_or(Ok(Decimal::ONE), |scaled_ui_amount| {
+                Decimal::from_f64(scaled_ui_amount.multiplier_at(unix_timestamp))
+                    .context("Invalid UI amount multiplier")
             })
-            .context("Token 0 mint not found")?;
+        };
+        Ok((
+            multiplier(token_mints.token0_scaled_ui_amount)?,
+            multiplier(token_mints.token1_scaled_ui_amount)?,
+        ))
+    }
 
-        let token1_mint = try_get_account_data(account_map, &self.pool_state.token_1_mint)
-            .ok()
-            .and_then(|account_data| {
-                StateWithExtensionsOwned::<spl_token_2022::state::Mint>::unpack(
-                    account_data.to_vec(),
-                )
+    /// Tradable reserves as wallets display them, in whole tokens scaled by
+    /// [`SegaAmm::ui_amount_multipliers`]
+    pub fn ui_reserves(&self) -> Result<(Decimal, Decimal)> {
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .context("Missing token mints and token programs")?;
+        let (total_token_0_amount, total_token_1_amount) = self.tradable_reserves()?;
+        let (token_0_multiplier, token_1_multiplier) = self.ui_amount_multipliers()?;
+
+        let ui_amount = |amount: u64, decimals: u8, multiplier: Decimal| {
+            Decimal::try_new(1, decimals.into())
                 .ok()
-            })
-            .context("Token 1 mint not found")?;
+                .and_then(|unit| Decimal::from(amount).checked_mul(unit))
+                .and_then(|amount| amount.checked_mul(multiplier))
+                .context("UI amount overflow")
+        };
+        Ok((
+            ui_amount(
+                total_token_0_amount,
+                token_mints.token0_mint.base.decimals,
+                token_0_multiplier,
+            )?,
+            ui_amount(
+                total_token_1_amount,
+                token_mints.token1_mint.base.decimals,
+                token_1_multiplier,
+            )?,
+        ))
+    }
 
-        self.token_mints_and_token_programs = Some(TokenMints {
-            token0: self.pool_state.token_0_mint,
-            token1: self.pool_state.token_1_mint,
-            token0_mint,
-            token1_mint,
-            token0_program: self.pool_state.token_0_program,
-            token1_program: self.pool_state.token_1_program,
-        });
+    /// Same as [`SegaAmm::spot_price`], in the UI amounts of [`SegaAmm::ui_reserves`]
+    pub fn ui_spot_price(&self, base_mint: &Pubkey) -> Result<Decimal> {
+        let (token_0_ui_amount, token_1_ui_amount) = self.ui_reserves()?;
+        let (base_ui_amount, quote_ui_amount) = if *base_mint == self.pool_state.token_0_mint {
+            (token_0_ui_amount, token_1_ui_amount)
+        } else if *base_mint == self.pool_state.token_1_mint {
+            (token_1_ui_amount, token_0_ui_amount)
+        } else {
+            return Err(anyhow!(
+                "Mint {} is not part of pool {}",
+                base_mint,
+                self.key
+            ));
+        };
+
+        quote_ui_amount
+            .checked_div(base_ui_amount)
+            .context("Empty reserve")
+    }
+
+    /// [`Amm::quote`] in UI amounts as [`SegaAmm::ui_reserves`] has them. `ui_amount` is the
+    /// input of exact in quotes and the output of exact out ones, and the result is what the
+    /// trader sends and receives. Converted to raw units, exact in amounts round down and exact
+    /// out ones up, so that neither more is sent nor less received than asked
+    pub fn quote_ui(
+        &self,
+        input_mint: Pubkey,
+        ui_amount: Decimal,
+        swap_mode: SwapMode,
+    ) -> Result<(Decimal, Decimal)> {
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .context("Missing token mints and token programs")?;
+        let (token_0_multiplier, token_1_multiplier) = self.ui_amount_multipliers()?;
+        // UI amount of a single raw unit
+        let ui_unit = |decimals: u8, multiplier: Decimal| {
+            Decimal::try_new(1, decimals.into())
+                .ok()
+                .and_then(|unit| unit.checked_mul(multiplier))
+                .context("UI amount overflow")
+        };
+        let token_0_unit = ui_unit(token_mints.token0_mint.base.decimals, token_0_multiplier)?;
+        let token_1_unit = ui_unit(token_mints.token1_mint.base.decimals, token_1_multiplier)?;
+
+        let (output_mint, input_unit, output_unit) = if input_mint == self.pool_state.token_0_mint {
+            (self.pool_state.token_1_mint, token_0_unit, token_1_unit)
+        } else if input_mint == self.pool_state.token_1_mint {
+            (self.pool_state.token_0_mint, token_1_unit, token_0_unit)
+        } else {
+            return Err(anyhow!("Mint {} is not in pool {}", input_mint, self.key));
+        };
+
+        let raw_amount = match swap_mode {
+            SwapMode::ExactIn => ui_amount
+                .checked_div(input_unit)
+                .map(|amount| amount.floor()),
+            SwapMode::ExactOut => ui_amount
+                .checked_div(output_unit)
+                .map(|amount| amount.ceil()),
+        };
+        let amount = raw_amount
+            .and_then(|amount| amount.to_u64())
+            .with_context(|| format!("Invalid UI amount {ui_amount}"))?;
+        let quote = self.quote(&QuoteParams {
+            amount,
+            input_mint,
+            output_mint,
+            swap_mode,
+        })?;
+
+        let amount_paid = match swap_mode {
+            SwapMode::ExactIn => amount,
+            SwapMode::ExactOut => quote.in_amount,
+        };
+        let to_ui = |amount: u64, unit: Decimal| {
+            Decimal::from(amount)
+                .checked_mul(unit)
+                .context("UI amount overflow")
+        };
+        Ok((
+            to_ui(amount_paid, input_unit)?,
+            to_ui(quote.out_amount, output_unit)?,
+        ))
+    }
 
-        let amm_config_data = try_get_account_data(account_map, &self.pool_state.amm_config)?;
-        self.amm_config = Some(AmmConfig::try_deserialize(&mut amm_config_data.as_ref())?);
+    /// Re-reads only the vaults and the pool state, whose accrued fees move with every swap.
+    /// The mints and config stay as the last [`Amm::update`] left them, so that has to run first
+    /// and again whenever those may have changed
+    pub fn update_vaults_only(&mut self, account_map: &AccountMap) -> Result<()> {
+        let pool_state_data = try_get_account_data(account_map, &self.key)?;
+        let pool_state = PoolState::try_deserialize(&mut pool_state_data.as_ref())?;
+        self.update_vaults(account_map, &pool_state);
+        self.pool_state = pool_state;
+        // The next full update refreshes everything again
+        self.update_fingerprint = None;
+        Ok(())
+    }
 
-        let get_unfrozen_token_amount = |token_vault| {
-            try_get_account_data(account_map, token_vault)
+    fn update_vaults(&mut self, account_map: &AccountMap, pool_state: &PoolState) {
+        let get_vault_state = |token_vault, mint| {
+            let token_account = try_get_account_data(account_map, token_vault)
                 .ok()
                 .and_then(|account_data| {
                     StateWithExtensions::<spl_token_2022::state::Account>::unpack(account_data).ok()
-                })
-                .and_then(|token_account| {
-                    if token_account.base.is_frozen() {
-                        None
-                    } else {
-                        Some(token_account.base.amount)
-                    }
-                })
+                });
+            let requires_memo = token_account.as_ref().is_some_and(memo_required);
+            let vault_state = match token_account {
+                Some(token_account) if token_account.base.mint != mint => VaultState::WrongMint,
+                Some(token_account) if token_account.base.is_frozen() => VaultState::Frozen,
+                Some(token_account) => VaultState::Amount(token_account.base.amount),
+                None => VaultState::Missing,
+            };
+            (vault_state, requires_memo)
         };
 
-        self.vault_0_amount = get_unfrozen_token_amount(&self.pool_state.token_0_vault);
-        self.vault_1_amount = get_unfrozen_token_amount(&self.pool_state.token_1_vault);
+        let (vault_0, vault_0_requires_memo) =
+            get_vault_state(&pool_state.token_0_vault, pool_state.token_0_mint);
+        let (vault_1, vault_1_requires_memo) =
+            get_vault_state(&pool_state.token_1_vault, pool_state.token_1_mint);
+        self.vault_0 = vault_0;
+        self.vault_1 = vault_1;
+        self.vaults_require_memo = (vault_0_requires_memo, vault_1_requires_memo);
+        self.accounting_inconsistent =
+            accrued_fees_exceed_vaults(pool_state, self.vault_0, self.vault_1);
+    }
 
-        Ok(())
+    /// Unlike [`SegaAmm::is_tradable`], swaps are enabled regardless of the pool `open_time`
+    pub fn status(&self) -> PoolStatus {
+        PoolStatus {
+            swap_enabled: self.pool_state.get_status_by_bit(PoolStatusBitIndex::Swap),
+            deposit_enabled: self
+                .pool_state
+                .get_status_by_bit(PoolStatusBitIndex::Deposit),
+            withdraw_enabled: self
+                .pool_state
+                .get_status_by_bit(PoolStatusBitIndex::Withdraw),
+        }
     }
 
-    fn quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
-        if !self.pool_state.get_status_by_bit(PoolStatusBitIndex::Swap)
-            || (self.timestamp.load(std::sync::atomic::Ordering::Relaxed) as u64)
-                < self.pool_state.open_time
-        {
-            return Err(anyhow!("Pool is not trading"));
+    /// Decoded pool state as of the last update. Its layout follows the on chain account and may
+    /// change across versions, [`SegaAmm::pool_info`] does not
+    pub fn pool_state(&self) -> &PoolState {
+        &self.pool_state
+    }
+
+    /// Snapshot of the pool state fields that does not follow its layout
+    pub fn pool_info(&self) -> PoolInfo {
+        let pool_state = &self.pool_state;
+        let status = self.status();
+        PoolInfo {
+            amm_config: pool_state.amm_config,
+            pool_creator: pool_state.pool_creator,
+            lp_mint: pool_state.lp_mint,
+            open_time: pool_state.open_time,
+            recent_epoch: pool_state.recent_epoch,
+            auth_bump: pool_state.auth_bump,
+            deposit_enabled: status.deposit_enabled,
+            withdraw_enabled: status.withdraw_enabled,
+            swap_enabled: status.swap_enabled,
+            lp_mint_decimals: pool_state.lp_mint_decimals,
+            mint_0_decimals: pool_state.mint_0_decimals,
+            mint_1_decimals: pool_state.mint_1_decimals,
+            lp_supply: pool_state.lp_supply,
+            protocol_fees_token_0: pool_state.protocol_fees_token_0,
+            protocol_fees_token_1: pool_state.protocol_fees_token_1,
+            fund_fees_token_0: pool_state.fund_fees_token_0,
+            fund_fees_token_1: pool_state.fund_fees_token_1,
         }
-        let amm_config = self.amm_config.as_ref().context("Missing AmmConfig")?;
+    }
 
-        let zero_for_one: bool = quote_params.input_mint == self.pool_state.token_0_mint;
+    pub fn lp_mint(&self) -> Pubkey {
+        self.pool_state.lp_mint
+    }
+
+    /// LP supply as tracked by the pool state, which is what the program prices deposits and
+    /// withdrawals with. Unlike the LP mint supply it leaves out the liquidity locked at creation
+    pub fn lp_supply(&self) -> u64 {
+        self.pool_state.lp_supply
+    }
 
+    /// Deposit of at most `amount_0` of token 0 and `amount_1` of token 1. Outside of the first
+    /// deposit, only the largest share matching the pool ratio is taken
+    pub fn quote_deposit(&self, amount_0: u64, amount_1: u64) -> Result<DepositQuote> {
+        let (lp_amount, vault_amount_0, vault_amount_1) =
+            self.deposit_vault_amounts(amount_0, amount_1)?;
         let TokenMints {
-            token0_mint: token_mint_0,
-            token1_mint: token_mint_1,
+            token0_transfer_fee_config,
+            token1_transfer_fee_config,
             ..
         } = self
             .token_mints_and_token_programs
-            .as_ref()
+            .as_deref()
             .ok_or(anyhow!("Missing token mints and token programs"))?;
+        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
 
-        let token_mint_0_transfer_fee_config =
-            token_mint_0.get_extension::<TransferFeeConfig>().ok();
-        let token_mint_1_transfer_fee_config =
-            token_mint_1.get_extension::<TransferFeeConfig>().ok();
+        Ok(DepositQuote {
+            lp_amount,
+            amount_0: vault_amount_0
+                .checked_add(get_transfer_inverse_fee(
+                    token0_transfer_fee_config.as_ref(),
+                    epoch,
+                    vault_amount_0,
+                )?)
+                .context("Amount 0 overflow")?,
+            amount_1: vault_amount_1
+                .checked_add(get_transfer_inverse_fee(
+                    token1_transfer_fee_config.as_ref(),
+                    epoch,
+                    vault_amount_1,
+                )?)
+                .context("Amount 1 overflow")?,
+        })
+    }
 
-        let (source_mint_transfer_fee_config, destination_mint_transfer_fee_config) =
-            if zero_for_one {
-                (
-                    token_mint_0_transfer_fee_config,
-                    token_mint_1_transfer_fee_config,
-                )
-            } else {
-                (
-                    token_mint_1_transfer_fee_config,
-                    token_mint_0_transfer_fee_config,
-                )
+    /// The LP amount minted for a deposit and the token 0 and token 1 amounts reaching the vaults
+    fn deposit_vault_amounts(&self, amount_0: u64, amount_1: u64) -> Result<(u64, u64, u64)> {
+        if !self
+            .pool_state
+            .get_status_by_bit(PoolStatusBitIndex::Deposit)
+        {
+            return Err(anyhow!("Pool deposits are disabled"));
+        }
+        let TokenMints {
+            token0_transfer_fee_config,
+            token1_transfer_fee_config,
+            ..
+        } = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .ok_or(anyhow!("Missing token mints and token programs"))?;
+        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
+
+        // What reaches the vaults once the transfer fees are taken
+        let net_amount_0 = amount_0.saturating_sub(get_transfer_fee(
+            token0_transfer_fee_config.as_ref(),
+            epoch,
+            amount_0,
+        )?);
+        let net_amount_1 = amount_1.saturating_sub(get_transfer_fee(
+            token1_transfer_fee_config.as_ref(),
+            epoch,
+            amount_1,
+        )?);
+
+        if self.pool_state.lp_supply == 0 {
+            // First deposit, both amounts are taken as is and set the pool ratio
+            let liquidity = U128::from(net_amount_0)
+                .checked_mul(net_amount_1.into())
+                .context("Liquidity overflow")?
+                .integer_sqrt()
+                .as_u64();
+            let lp_amount = liquidity
+                .checked_sub(LOCK_LP_AMOUNT)
+                .filter(|lp_amount| *lp_amount > 0)
+                .context("Deposit too small")?;
+            Ok((lp_amount, net_amount_0, net_amount_1))
+        } else {
+            let (total_token_0_amount, total_token_1_amount) = self.tradable_reserves()?;
+            let lp_supply = u128::from(self.pool_state.lp_supply);
+            let lp_amount_for = |amount: u64, total_amount: u64| {
+                u128::from(amount)
+                    .checked_mul(lp_supply)?
+                    .checked_div(total_amount.into())
             };
+            let lp_amount = lp_amount_for(net_amount_0, total_token_0_amount)
+                .zip(lp_amount_for(net_amount_1, total_token_1_amount))
+                .map(|(lp_amount_0, lp_amount_1)| lp_amount_0.min(lp_amount_1))
+                .context("Empty reserve")?;
 
-        let amount = quote_params.amount;
-        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
-        let actual_amount_in = if let Some(transfer_fee_config) = source_mint_transfer_fee_config {
-            amount.saturating_sub(
-                transfer_fee_config
-                    .calculate_epoch_fee(epoch, amount)
-                    .context("Fee calculation failure")?,
+            let TradingTokenResult {
+                token_0_amount,
+                token_1_amount,
+            } = CurveCalculator::lp_tokens_to_trading_tokens(
+                lp_amount,
+                lp_supply,
+                total_token_0_amount.into(),
+                total_token_1_amount.into(),
+                RoundDirection::Ceiling,
             )
-        } else {
-            amount
-        };
-        if actual_amount_in == 0 {
-            return Err(anyhow!("Amount too low"));
+            .context("Deposit calculation failure")?;
+            if lp_amount == 0 || token_0_amount == 0 || token_1_amount == 0 {
+                return Err(anyhow!("Deposit too small"));
+            }
+            Ok((
+                lp_amount.try_into()?,
+                token_0_amount.try_into()?,
+                token_1_amount.try_into()?,
+            ))
         }
+    }
 
-        // Calculate the trade amounts
-        let (total_token_0_amount, total_token_1_amount) = match vault_amount_without_fee(
-            &self.pool_state,
-            self.vault_0_amount.context("Vault 0 missing or frozen")?,
-            self.vault_1_amount.context("Vault 1 missing or frozen")?,
-        ) {
-            (Some(vault_0), Some(vault_1)) => (vault_0, vault_1),
-            _ => return Err(anyhow!("Vault amount underflow")),
-        };
+    /// Copy of the pool after a deposit of at most `amount_0` of token 0 and `amount_1` of token 1,
+    /// as [`SegaAmm::quote_deposit`] prices it, to quote against the deeper reserves
+    pub fn with_simulated_deposit(&self, amount_0: u64, amount_1: u64) -> Result<SegaAmm> {
+        let (lp_amount, vault_amount_0, vault_amount_1) =
+            self.deposit_vault_amounts(amount_0, amount_1)?;
+        let add_to_vault =
+            |vault: VaultState, vault_amount: u64, index: u8| -> Result<VaultState> {
+                vault
+                    .amount(index)?
+                    .checked_add(vault_amount)
+                    .map(VaultState::Amount)
+                    .with_context(|| format!("Vault {index} overflow"))
+            };
+
+        let mut amm = self.clone();
+        amm.vault_0 = add_to_vault(self.vault_0, vault_amount_0, 0)?;
+        amm.vault_1 = add_to_vault(self.vault_1, vault_amount_1, 1)?;
+        amm.pool_state.lp_supply = self
+            .pool_state
+            .lp_supply
+            .checked_add(lp_amount)
+            .context("LP supply overflow")?;
+        // The clone is not the pool anymore, its next update reloads the actual accounts
+        amm.update_fingerprint = None;
+        Ok(amm)
+    }
+
+    /// Token 0 and token 1 amounts received, transfer fees deducted, for burning `lp_amount`
+    pub fn quote_withdraw(&self, lp_amount: u64) -> Result<(u64, u64)> {
+        if !self
+            .pool_state
+            .get_status_by_bit(PoolStatusBitIndex::Withdraw)
+        {
+            return Err(anyhow!("Pool withdrawals are disabled"));
+        }
+        if lp_amount > self.pool_state.lp_supply {
+            return Err(anyhow!("LP amount exceeds the LP supply"));
+        }
+        let TokenMints {
+            token0_transfer_fee_config,
+            token1_transfer_fee_config,
+            ..
+        } = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .ok_or(anyhow!("Missing token mints and token programs"))?;
+        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
 
-        let swap_result = CurveCalculator::swap_base_input(
-            u128::from(actual_amount_in),
+        // The reserves already exclude the protocol and fund fees owed by the pool
+        let (total_token_0_amount, total_token_1_amount) = self.tradable_reserves()?;
+        let TradingTokenResult {
+            token_0_amount,
+            token_1_amount,
+        } = CurveCalculator::lp_tokens_to_trading_tokens(
+            lp_amount.into(),
+            self.pool_state.lp_supply.into(),
             total_token_0_amount.into(),
             total_token_1_amount.into(),
-            amm_config.trade_fee_rate,
-            amm_config.protocol_fee_rate,
-            amm_config.fund_fee_rate,
+            RoundDirection::Floor,
         )
-        .context("Swap failed")?;
-    
-        let amount_out: u64 = swap_result.destination_amount_swapped.try_into()?;
-        let actual_amount_out = if let Some(transfer_fee_config) = destination_mint_transfer_fee_config {
-            amount_out.saturating_sub(
-                transfer_fee_config
-                    .calculate_epoch_fee(epoch, amount_out)
-                    .context("Fee calculation failure")?,
-            )
-        } else {
-            amount_out
-        };
+        .context("Withdraw calculation failure")?;
+        if token_0_amount == 0 || token_1_amount == 0 {
+            return Err(anyhow!("Withdraw too small"));
+        }
 
-        let fee_amount = swap_result.trade_fee;
+        let token_0_amount: u64 = token_0_amount.try_into()?;
+        let token_1_amount: u64 = token_1_amount.try_into()?;
+        Ok((
+            token_0_amount.saturating_sub(get_transfer_fee(
+                token0_transfer_fee_config.as_ref(),
+                epoch,
+                token_0_amount,
+            )?),
+            token_1_amount.saturating_sub(get_transfer_fee(
+                token1_transfer_fee_config.as_ref(),
+                epoch,
+                token_1_amount,
+            )?),
+        ))
+    }
 
-        Ok(Quote {
-            in_amount: swap_result.source_amount_swapped.try_into()?,
-            out_amount: actual_amount_out,
-            fee_mint: quote_params.input_mint,
-            fee_amount: fee_amount.try_into()?,
-            fee_pct: rust_decimal::Decimal::from(fee_amount) / rust_decimal::Decimal::from(100),            
-            ..Default::default()
+    /// Whether swaps are enabled and the pool has opened at the current clock timestamp
+    pub fn is_tradable(&self) -> bool {
+        self.is_tradable_at(self.timestamp.load(std::sync::atomic::Ordering::Relaxed))
+    }
 
-        })
+    fn is_tradable_at(&self, unix_timestamp: i64) -> bool {
+        self.pool_state.get_status_by_bit(PoolStatusBitIndex::Swap)
+            && (unix_timestamp as u64) >= self.pool_state.open_time
     }
 
-    fn get_accounts_len(&self) -> usize {
-        14
+    /// Makes `update` decode the amm config through `amm_config_cache`, shared with other pools
+    pub fn set_amm_config_cache(&mut self, amm_config_cache: AmmConfigCache) {
+        self.amm_config_cache = Some(amm_config_cache);
     }
 
-    fn get_swap_and_account_metas(&self, swap_params: &SwapParams) -> Result<SwapAndAccountMetas> {
-        if self.token_mints_and_token_programs.is_none() {
-            return Err(anyhow!("Missing token mints and token programs"));
-        }
+    /// Lets the quotes ignore the pool `open_time`, to plan trades ahead of a scheduled launch.
+    /// Pools with swaps disabled still fail to quote, and [`SegaAmm::is_tradable`] is unaffected
+    pub fn set_quote_before_open_time(&mut self, quote_before_open_time: bool) {
+        self.quote_before_open_time = quote_before_open_time;
+    }
 
-        let TokenMints {
-            token0_program: token_0_token_program,
-            token1_program: token_1_token_program,
-            ..
-        } = self
-            .token_mints_and_token_programs
-            .as_ref()
-            .ok_or(anyhow!("Missing token mints and token programs"))?;
+    /// Lets the quotes scale down an amount the pool cannot fill, instead of failing, to the
+    /// largest one it can. Such quotes have [`SegaQuote::partial_fill`] set, while [`Amm::quote`]
+    /// returns the reduced amounts with nothing else to tell them apart from the requested ones.
+    /// Only an unreachable output, a curve failure or an amount overflow is scaled down, the
+    /// other [`SegaQuoteError`]s and plain errors fail the quote as usual
+    pub fn set_partial_fill(&mut self, partial_fill: bool) {
+        self.partial_fill = partial_fill;
+    }
 
-        let (
-            input_token_program,
-            input_vault,
-            input_token_mint,
-            output_token_program,
-            output_vault,
-            output_token_mint,
-        ) = if swap_params.source_mint == self.pool_state.token_0_mint {
+    /// Makes the quotes fail when their [`SegaQuote::price_impact_pct`] exceeds
+    /// `max_price_impact_bps`, None lifts the limit
+    pub fn set_max_price_impact_bps(&mut self, max_price_impact_bps: Option<u16>) {
+        self.max_price_impact_bps = max_price_impact_bps;
+    }
+
+    /// Reports the outcome and duration of every quote to `quote_observer`, None stops reporting.
+    /// Clones share the observer
+    pub fn set_quote_observer(&mut self, quote_observer: Option<Arc<dyn QuoteObserver>>) {
+        self.quote_observer = quote_observer;
+    }
+
+    /// The `(trade_fee_rate, protocol_fee_rate, fund_fee_rate)` of the pool config. The trade fee is
+    /// out of `FEE_RATE_DENOMINATOR_VALUE`, the protocol and fund ones are shares of the trade fee
+    pub fn fee_rates(&self) -> Result<(u64, u64, u64)> {
+        let amm_config = self.amm_config.as_ref().context("Missing AmmConfig")?;
+        Ok((
+            amm_config.trade_fee_rate,
+            amm_config.protocol_fee_rate,
+            amm_config.fund_fee_rate,
+        ))
+    }
+
+    /// Whether one side of the pool is wrapped SOL. The swap only moves SPL tokens, so the caller
+    /// has to create and fund a temporary wSOL account beforehand and close it afterwards
+    pub fn uses_wrapped_sol(&self) -> bool {
+        self.get_reserve_mints()
+            .contains(&spl_token::native_mint::ID)
+    }
+
+    /// Instantaneous price of `base_mint` denominated in the other reserve mint, in raw token units
+    pub fn spot_price(&self, base_mint: &Pubkey) -> Result<Decimal> {
+        self.amm_config.as_ref().context("Missing AmmConfig")?;
+        let (total_token_0_amount, total_token_1_amount) = self.tradable_reserves()?;
+
+        let (base_amount, quote_amount) = if *base_mint == self.pool_state.token_0_mint {
+            (total_token_0_amount, total_token_1_amount)
+        } else if *base_mint == self.pool_state.token_1_mint {
+            (total_token_1_amount, total_token_0_amount)
+        } else {
+            return Err(anyhow!(
+                "Mint {} is not part of pool {}",
+                base_mint,
+                self.key
+            ));
+        };
+
+        Decimal::from(quote_amount)
+            .checked_div(Decimal::from(base_amount))
+            .context("Empty reserve")
+    }
+
+    /// Average price the quote for `params` executes at, output per input in raw token units. The
+    /// input is what the trader sends and the output what they receive, so every fee is accounted
+    /// for, unlike in [`SegaAmm::spot_price`] of the input mint
+    pub fn effective_price(&self, params: &QuoteParams) -> Result<Decimal> {
+        let quote = self.quote(params)?;
+        let amount_paid = match params.swap_mode {
+            SwapMode::ExactIn => params.amount,
+            SwapMode::ExactOut => quote.in_amount,
+        };
+        Decimal::from(quote.out_amount)
+            .checked_div(Decimal::from(amount_paid))
+            .context("Empty amount")
+    }
+
+    /// Time weighted average price of token 0 denominated in token 1, in raw token units,
+    /// over at least the last `window_seconds`
+    pub fn twap_price(&self, window_seconds: u32) -> Result<Decimal> {
+        let observation_state = self
+            .observation_state
+            .as_ref()
+            .context("Missing observation state")?;
+        let now = u64::try_from(self.timestamp.load(std::sync::atomic::Ordering::Relaxed))?;
+
+        let (token_0_price_x32, _) = observation_state
+            .twap_price_x32(now, window_seconds.into())
+            .context("Window exceeds the available observations")?;
+
+        Decimal::from_u128(token_0_price_x32)
+            .and_then(|price_x32| price_x32.checked_div(Decimal::from_u128(Q32)?))
+            .context("TWAP price overflow")
+    }
+
+    /// Block timestamp of the most recent observation, `None` when the observation state is not loaded
+    /// or not initialized yet. Compare it with the clock to judge how stale the oracle is
+    pub fn last_observation_timestamp(&self) -> Option<i64> {
+        Some(self.latest_cumulative_price()?.1)
+    }
+
+    /// Cumulative token 0 price, Q32.32, and block timestamp of the most recent observation, read
+    /// together so that external TWAP windows pair them up right. The cumulative price wraps
+    /// around on chain, differences between two readings have to wrap as well
+    pub fn latest_cumulative_price(&self) -> Option<(u128, i64)> {
+        let observation_state = self.observation_state.as_ref()?;
+        if !observation_state.initialized {
+            return None;
+        }
+        let observations = observation_state.observations;
+        let latest = observations.get(usize::from(observation_state.observation_index))?;
+        Some((
+            latest.cumulative_token_0_price_x32,
+            i64::try_from(latest.block_timestamp).ok()?,
+        ))
+    }
+
+    /// Same as [`Amm::quote`], at the given clock instead of the shared one
+    pub fn quote_at(&self, params: &QuoteParams, epoch: u64, unix_timestamp: i64) -> Result<Quote> {
+        Ok(self.quote_detailed_at(params, epoch, unix_timestamp)?.quote)
+    }
+
+    /// Same as [`Amm::quote`], except that when exactly one vault account is missing its balance
+    /// is reconstructed from the pool state accounting: the LP supply and the accrued protocol and
+    /// fund fees of that side, along with the other reserve.
+    ///
+    /// The pool state does not record the reserves themselves, so this is a best effort. The LP
+    /// supply squared bounds the product of the reserves net of fees from below, deposits and
+    /// withdrawals keeping them in proportion while swaps only grow it with their trade fees.
+    /// The missing reserve is taken at that bound, so it falls short of the actual one by the trade
+    /// fees the pool kept since it opened, and the shortfall tilts the quoted price towards that side.
+    /// A pool without LP supply has nothing to bound it with and fails the same way as
+    /// [`Amm::quote`]. Frozen vaults are never estimated, the swap would fail on chain.
+    pub fn quote_resilient(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        let pool_state = &self.pool_state;
+        if pool_state.lp_supply == 0 {
+            return self.quote(quote_params);
+        }
+        // Smallest reserve net of fees whose product with `other_amount` reaches the LP supply squared
+        let min_amount = |other_amount: u64| {
+            let (lp_supply, other_amount) =
+                (u128::from(pool_state.lp_supply), u128::from(other_amount));
+            if other_amount == 0 {
+                return None;
+            }
+            u64::try_from((lp_supply * lp_supply).div_ceil(other_amount)).ok()
+        };
+
+        let mut amm = self.clone();
+        match (self.vault_0, self.vault_1) {
+            (VaultState::Amount(vault_0_amount), VaultState::Missing) => {
+                let total_token_0_amount = vault_amount_without_fee(pool_state, vault_0_amount, 0)
+                    .0
+                    .context("Vault amount underflow")?;
+                amm.vault_1 = VaultState::Amount(
+                    min_amount(total_token_0_amount)
+                        .and_then(|amount| amount.checked_add(pool_state.protocol_fees_token_1))
+                        .and_then(|amount| amount.checked_add(pool_state.fund_fees_token_1))
+                        .context("Vault 1 estimation failure")?,
+                );
+            }
+            (VaultState::Missing, VaultState::Amount(vault_1_amount)) => {
+                let total_token_1_amount = vault_amount_without_fee(pool_state, 0, vault_1_amount)
+                    .1
+                    .context("Vault amount underflow")?;
+                amm.vault_0 = VaultState::Amount(
+                    min_amount(total_token_1_amount)
+                        .and_then(|amount| amount.checked_add(pool_state.protocol_fees_token_0))
+                        .and_then(|amount| amount.checked_add(pool_state.fund_fees_token_0))
+                        .context("Vault 0 estimation failure")?,
+                );
+            }
+            _ => return self.quote(quote_params),
+        }
+        amm.quote(quote_params)
+    }
+
+    /// Quotes of each of `amounts`, in order, all at the same clock reading. The transfer fee
+    /// configs are cached on update so only the curve is evaluated per amount
+    pub fn quote_ladder(
+        &self,
+        input_mint: Pubkey,
+        amounts: &[u64],
+        swap_mode: SwapMode,
+    ) -> Vec<Result<Quote>> {
+        let output_mint = if input_mint == self.pool_state.token_0_mint {
+            self.pool_state.token_1_mint
+        } else {
+            self.pool_state.token_0_mint
+        };
+        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
+        let unix_timestamp = self.timestamp.load(std::sync::atomic::Ordering::Relaxed);
+
+        amounts
+            .iter()
+            .map(|&amount| {
+                self.quote_at(
+                    &QuoteParams {
+                        amount,
+                        input_mint,
+                        output_mint,
+                        swap_mode,
+                    },
+                    epoch,
+                    unix_timestamp,
+                )
+            })
+            .collect()
+    }
+
+    /// `samples` evenly spaced exact in amounts of `input_mint` up to `max_input`, along with what
+    /// each of them receives, from a single [`SegaAmm::quote_ladder`]. The curve starts at the
+    /// origin, which is left out. Amounts too low to get through the transfer fee receive zero
+    pub fn depth_curve(
+        &self,
+        input_mint: Pubkey,
+        max_input: u64,
+        samples: usize,
+    ) -> Result<Vec<(u64, u64)>> {
+        if samples == 0 || max_input == 0 {
+            return Ok(Vec::new());
+        }
+        let amounts: Vec<u64> = (1..=samples as u128)
+            .map(|sample| (u128::from(max_input) * sample / samples as u128) as u64)
+            .collect();
+
+        amounts
+            .iter()
+            .zip(self.quote_ladder(input_mint, &amounts, SwapMode::ExactIn))
+            .map(|(&amount, quote)| match quote {
+                Ok(quote) => Ok((amount, quote.out_amount)),
+                Err(error)
+                    if error.downcast_ref::<SegaQuoteError>()
+                        == Some(&SegaQuoteError::AmountTooLow) =>
+                {
+                    Ok((amount, 0))
+                }
+                Err(error) => Err(error),
+            })
+            .collect()
+    }
+
+    /// Largest `input_mint` amount swapped in with a price impact of at most `max_impact_bps`,
+    /// transfer and trade fees included. Zero when not even the smallest input stays under it
+    pub fn max_input_for_impact(&self, input_mint: Pubkey, max_impact_bps: u16) -> Result<u64> {
+        let output_mint = if input_mint == self.pool_state.token_0_mint {
+            self.pool_state.token_1_mint
+        } else if input_mint == self.pool_state.token_1_mint {
+            self.pool_state.token_0_mint
+        } else {
+            return Err(anyhow!("Mint {} is not in pool {}", input_mint, self.key));
+        };
+        let max_impact_pct = Decimal::new(max_impact_bps.into(), 4);
+        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
+        let unix_timestamp = self.timestamp.load(std::sync::atomic::Ordering::Relaxed);
+
+        let start = std::time::Instant::now();
+        let quote = |amount| {
+            let quote_params = QuoteParams {
+                amount,
+                input_mint,
+                output_mint,
+                swap_mode: SwapMode::ExactIn,
+            };
+            self.quote_filled_at(&quote_params, epoch, unix_timestamp, false)
+        };
+
+        // The impact grows with the input, up to the rounding of the output, so binary search
+        // the last amount within it. The probes are not reported to the quote observer, which
+        // only hears of the quote of that amount, or of the last probe when none is within it
+        let (mut low, mut high, mut final_quote) = (0, u64::MAX, None);
+        while low < high {
+            let middle = low + (high - low).div_ceil(2);
+            let sega_quote = quote(middle);
+            let within_impact = sega_quote
+                .as_ref()
+                .is_ok_and(|sega_quote| sega_quote.price_impact_pct <= max_impact_pct);
+            if within_impact {
+                low = middle;
+            } else {
+                high = middle - 1;
+            }
+            if within_impact || low == 0 {
+                final_quote = Some(sega_quote);
+            }
+        }
+        if let Some(final_quote) = &final_quote {
+            self.notify_quote_observer(start.elapsed(), final_quote);
+        }
+        Ok(low)
+    }
+
+    /// Rate of swapping the output of the forward quote straight back, in input tokens per output token.
+    /// Both legs are quoted against the same reserves, so multiplied with the forward rate this gives
+    /// the round trip loss to fees and slippage
+    pub fn implied_reverse_rate(&self, params: &QuoteParams) -> Result<Decimal> {
+        let epoch = self.epoch.load(std::sync::atomic::Ordering::Relaxed);
+        let unix_timestamp = self.timestamp.load(std::sync::atomic::Ordering::Relaxed);
+
+        let quote = self.quote_at(params, epoch, unix_timestamp)?;
+        let reverse_quote = self.quote_at(
+            &QuoteParams {
+                amount: quote.out_amount,
+                input_mint: params.output_mint,
+                output_mint: params.input_mint,
+                swap_mode: SwapMode::ExactIn,
+            },
+            epoch,
+            unix_timestamp,
+        )?;
+
+        Decimal::from(reverse_quote.out_amount)
+            .checked_div(Decimal::from(quote.out_amount))
+            .context("Empty forward quote")
+    }
+
+    /// Fraction of `amount` lost by swapping it in and the output straight back out, the second
+    /// leg against the reserves the first one leaves. The second leg gives back the curve
+    /// slippage of the first, so the loss is roughly twice the trade fee rate, plus any transfer
+    /// fees and the rounding to whole units, which weighs more the shallower the pool
+    pub fn round_trip_loss(&self, input_mint: Pubkey, amount: u64) -> Result<Decimal> {
+        let output_mint = if input_mint == self.pool_state.token_0_mint {
+            self.pool_state.token_1_mint
+        } else if input_mint == self.pool_state.token_1_mint {
+            self.pool_state.token_0_mint
+        } else {
+            return Err(anyhow!("Mint {} is not in pool {}", input_mint, self.key));
+        };
+
+        let sega_quote = self.quote_detailed(&QuoteParams {
+            amount,
+            input_mint,
+            output_mint,
+            swap_mode: SwapMode::ExactIn,
+        })?;
+        let reverse_quote = self
+            .with_simulated_swap(input_mint == self.pool_state.token_0_mint, &sega_quote)?
+            .quote(&QuoteParams {
+                amount: sega_quote.quote.out_amount,
+                input_mint: output_mint,
+                output_mint: input_mint,
+                swap_mode: SwapMode::ExactIn,
+            })?;
+
+        Decimal::from(amount.saturating_sub(reverse_quote.out_amount))
+            .checked_div(Decimal::from(amount))
+            .context("Empty amount")
+    }
+
+    /// Copy of the pool after the exact in swap of `sega_quote`: the vaults move by what entered
+    /// and left the curve, and the protocol and fund share of the trade fee accrues to the pool
+    fn with_simulated_swap(&self, zero_for_one: bool, sega_quote: &SegaQuote) -> Result<SegaAmm> {
+        let mut amm = self.clone();
+        let (vault_in, vault_out, index_in, index_out) = if zero_for_one {
+            (&mut amm.vault_0, &mut amm.vault_1, 0, 1)
+        } else {
+            (&mut amm.vault_1, &mut amm.vault_0, 1, 0)
+        };
+        *vault_in = vault_in
+            .amount(index_in)?
+            .checked_add(sega_quote.quote.in_amount)
+            .map(VaultState::Amount)
+            .with_context(|| format!("Vault {index_in} overflow"))?;
+        *vault_out = vault_out
+            .amount(index_out)?
+            .checked_sub(sega_quote.gross_out_amount)
+            .map(VaultState::Amount)
+            .with_context(|| format!("Vault {index_out} underflow"))?;
+
+        let FeeBreakdown {
+            protocol_fee,
+            fund_fee,
+            ..
+        } = sega_quote.fee_breakdown;
+        let pool_state = &mut amm.pool_state;
+        let (protocol_fees, fund_fees) = if zero_for_one {
             (
-                *token_0_token_program,
-                self.pool_state.token_0_vault,
-                self.pool_state.token_0_mint,
-                *token_1_token_program,
-                self.pool_state.token_1_vault,
-                self.pool_state.token_1_mint,
+                &mut pool_state.protocol_fees_token_0,
+                &mut pool_state.fund_fees_token_0,
             )
         } else {
             (
-                *token_1_token_program,
-                self.pool_state.token_1_vault,
-                self.pool_state.token_1_mint,
-                *token_0_token_program,
-                self.pool_state.token_0_vault,
-                self.pool_state.token_0_mint,
+                &mut pool_state.protocol_fees_token_1,
+                &mut pool_state.fund_fees_token_1,
             )
         };
+        *protocol_fees = protocol_fees
+            .checked_add(protocol_fee)
+            .context("Protocol fees overflow")?;
+        *fund_fees = fund_fees
+            .checked_add(fund_fee)
+            .context("Fund fees overflow")?;
+        // Same as a simulated deposit, the clone is not the pool anymore
+        amm.update_fingerprint = None;
+        Ok(amm)
+    }
 
-        let account_metas = SegaSwap {
-            program: self.program_id,
-            payer: swap_params.token_transfer_authority,
-            authority: self.get_authority(),
-            amm_config: self.pool_state.amm_config,
-            pool_state: self.key,
-            input_token_account: swap_params.source_token_account,
-            output_token_account: swap_params.destination_token_account,
-            input_vault,
-            output_vault,
-            input_token_program,
-            output_token_program,
-            input_token_mint,
-            output_token_mint,
-            observation_state: self.pool_state.observation_key,
+    /// Most profitable round trip from token 0 through one pool and back through `other`, a pool
+    /// of the same pair, or None when the spot prices are within the combined trade fees.
+    /// The size comes from the constant product formula and the profit from actual quotes, so
+    /// transfer fees can still turn it into None
+    pub fn arbitrage_opportunity(&self, other: &SegaAmm) -> Result<Option<ArbOpportunity>> {
+        let (input_mint, intermediate_mint) =
+            (self.pool_state.token_0_mint, self.pool_state.token_1_mint);
+        let mut other_mints = other.get_reserve_mints();
+        other_mints.sort();
+        let mut mints = vec![input_mint, intermediate_mint];
+        mints.sort();
+        if other_mints != mints {
+            return Err(anyhow!(
+                "Pools {} and {} do not share a pair",
+                self.key,
+                other.key
+            ));
         }
-        .to_account_metas(None);
 
-        Ok(SwapAndAccountMetas {
-            swap: Swap::RaydiumCP,
-            account_metas,
+        // Reserves in `(input_mint, intermediate_mint)` order, and the share the trade fee leaves
+        let pool_terms = |pool: &SegaAmm| -> Result<(f64, f64, f64)> {
+            let (total_token_0_amount, total_token_1_amount) = pool.tradable_reserves()?;
+            let (trade_fee_rate, _, _) = pool.fee_rates()?;
+            let (input_amount, intermediate_amount) = if pool.pool_state.token_0_mint == input_mint
+            {
+                (total_token_0_amount, total_token_1_amount)
+            } else {
+                (total_token_1_amount, total_token_0_amount)
+            };
+            Ok((
+                input_amount as f64,
+                intermediate_amount as f64,
+                1.0 - trade_fee_rate as f64 / FEE_RATE_DENOMINATOR_VALUE as f64,
+            ))
+        };
+
+        for (buy_pool, sell_pool) in [(self, other), (other, self)] {
+            let (buy_input, buy_intermediate, buy_gamma) = pool_terms(buy_pool)?;
+            let (sell_input, sell_intermediate, sell_gamma) = pool_terms(sell_pool)?;
+
+            // Both swaps compose into out = a * in / (c + d * in), the profit out - in peaks where
+            // the derivative is 1. There is a profit only when a > c, the price gap beating the fees
+            let a = buy_gamma * sell_gamma * buy_intermediate * sell_input;
+            let c = buy_input * sell_intermediate;
+            let d = buy_gamma * (sell_intermediate + sell_gamma * buy_intermediate);
+            if a <= c {
+                continue;
+            }
+            // Saturates at the u64 bounds
+            let in_amount = ((a.sqrt() * c.sqrt() - c) / d) as u64;
+            if in_amount == 0 {
+                continue;
+            }
+
+            let intermediate_quote = buy_pool.quote(&QuoteParams {
+                amount: in_amount,
+                input_mint,
+                output_mint: intermediate_mint,
+                swap_mode: SwapMode::ExactIn,
+            })?;
+            let quote = sell_pool.quote(&QuoteParams {
+                amount: intermediate_quote.out_amount,
+                input_mint: intermediate_mint,
+                output_mint: input_mint,
+                swap_mode: SwapMode::ExactIn,
+            })?;
+            if quote.out_amount > in_amount {
+                return Ok(Some(ArbOpportunity {
+                    buy_pool: buy_pool.key,
+                    sell_pool: sell_pool.key,
+                    input_mint,
+                    in_amount,
+                    out_amount: quote.out_amount,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Same as [`Amm::quote`], along with how far in bps the spot price has moved away from the
+    /// TWAP over `twap_window_seconds`. The deviation is None when the observations are missing
+    /// or do not cover the window
+    pub fn quote_with_twap_deviation(
+        &self,
+        quote_params: &QuoteParams,
+        twap_window_seconds: u32,
+    ) -> Result<(Quote, Option<u64>)> {
+        let quote = self.quote(quote_params)?;
+        let Ok(twap_price) = self.twap_price(twap_window_seconds) else {
+            return Ok((quote, None));
+        };
+        let spot_price = self.spot_price(&self.pool_state.token_0_mint)?;
+
+        let twap_deviation_bps = (spot_price - twap_price)
+            .abs()
+            .checked_div(twap_price)
+            .and_then(|deviation| deviation.checked_mul(Decimal::from(10_000)))
+            .and_then(|deviation_bps| deviation_bps.round().to_u64());
+        Ok((quote, twap_deviation_bps))
+    }
+
+    /// Same as [`Amm::quote`], with the details that do not fit in [`Quote`].
+    /// Like [`Amm::quote`], the `amount` of exact in quotes and the `in_amount` of exact out ones
+    /// are what the trader sends, the source transfer fee included, see [`SegaAmm::quote_net_input`]
+    pub fn quote_detailed(&self, quote_params: &QuoteParams) -> Result<SegaQuote> {
+        self.quote_detailed_at(
+            quote_params,
+            self.epoch.load(std::sync::atomic::Ordering::Relaxed),
+            self.timestamp.load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Same as [`SegaAmm::quote_detailed`], at the given clock instead of the shared one
+    pub fn quote_detailed_at(
+        &self,
+        quote_params: &QuoteParams,
+        epoch: u64,
+        unix_timestamp: i64,
+    ) -> Result<SegaQuote> {
+        self.quote_detailed_at_with(quote_params, epoch, unix_timestamp, false)
+    }
+
+    /// Same as [`Amm::quote`], with the `amount` of exact in quotes and the `in_amount` of exact
+    /// out ones net of the source transfer fee, that is what reaches the vault, for callers
+    /// accounting for that fee themselves. The destination transfer fee still comes off `out_amount`
+    pub fn quote_net_input(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        Ok(self
+            .quote_detailed_at_with(
+                quote_params,
+                self.epoch.load(std::sync::atomic::Ordering::Relaxed),
+                self.timestamp.load(std::sync::atomic::Ordering::Relaxed),
+                true,
+            )?
+            .quote)
+    }
+
+    fn quote_detailed_at_with(
+        &self,
+        quote_params: &QuoteParams,
+        epoch: u64,
+        unix_timestamp: i64,
+        net_input: bool,
+    ) -> Result<SegaQuote> {
+        if self.quote_observer.is_none() {
+            return self.quote_filled_at(quote_params, epoch, unix_timestamp, net_input);
+        }
+        let start = std::time::Instant::now();
+        let result = self.quote_filled_at(quote_params, epoch, unix_timestamp, net_input);
+        self.notify_quote_observer(start.elapsed(), &result);
+        result
+    }
+
+    fn notify_quote_observer(&self, elapsed: std::time::Duration, result: &Result<SegaQuote>) {
+        let Some(quote_observer) = &self.quote_observer else {
+            return;
+        };
+        match result {
+            Ok(_) => quote_observer.on_quote_success(elapsed),
+            Err(error) => quote_observer
+                .on_quote_failure(elapsed, error.downcast_ref::<SegaQuoteError>().copied()),
+        }
+    }
+
+    fn quote_filled_at(
+        &self,
+        quote_params: &QuoteParams,
+        epoch: u64,
+        unix_timestamp: i64,
+        net_input: bool,
+    ) -> Result<SegaQuote> {
+        let error = match self.quote_full_at(quote_params, epoch, unix_timestamp, net_input) {
+            Ok(sega_quote) => return Ok(sega_quote),
+            Err(error) if !self.partial_fill => return Err(error),
+            Err(error) => error,
+        };
+        // A price impact limit is the caller's to relax, and the other failures do not depend on
+        // the amount, so none of them is worth a smaller one
+        if !matches!(
+            error.downcast_ref::<SegaQuoteError>(),
+            Some(
+                SegaQuoteError::OutputUnreachable
+                    | SegaQuoteError::CurveFailure
+                    | SegaQuoteError::AmountOverflow
+            )
+        ) {
+            return Err(error);
+        }
+
+        // Quoting only gets harder as the amount grows, so search for the largest one that works.
+        // Failing all the way down leaves nothing to fill and surfaces the original error
+        let quote_amount = |amount| {
+            let quote_params = QuoteParams {
+                amount,
+                ..*quote_params
+            };
+            self.quote_full_at(&quote_params, epoch, unix_timestamp, net_input)
+                .ok()
+        };
+        let (mut low, mut high, mut best) = (0, quote_params.amount, None);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            match quote_amount(mid) {
+                Some(sega_quote) => {
+                    low = mid;
+                    best = Some(sega_quote);
+                }
+                None => high = mid,
+            }
+        }
+        let mut sega_quote = best.ok_or(error)?;
+        sega_quote.partial_fill = true;
+        Ok(sega_quote)
+    }
+
+    fn quote_full_at(
+        &self,
+        quote_params: &QuoteParams,
+        epoch: u64,
+        unix_timestamp: i64,
+        net_input: bool,
+    ) -> Result<SegaQuote> {
+        let quotable = if self.quote_before_open_time {
+            self.pool_state.get_status_by_bit(PoolStatusBitIndex::Swap)
+        } else {
+            self.is_tradable_at(unix_timestamp)
+        };
+        if !quotable {
+            // A zero timestamp is a clock nobody advanced rather than a pool yet to open
+            if unix_timestamp == 0 && self.pool_state.get_status_by_bit(PoolStatusBitIndex::Swap) {
+                return Err(SegaQuoteError::ClockUninitialized.into());
+            }
+            return Err(SegaQuoteError::NotTrading.into());
+        }
+        let amm_config = self.amm_config.as_ref().context("Missing AmmConfig")?;
+
+        let mints = (quote_params.input_mint, quote_params.output_mint);
+        let zero_for_one: bool =
+            if mints == (self.pool_state.token_0_mint, self.pool_state.token_1_mint) {
+                true
+            } else if mints == (self.pool_state.token_1_mint, self.pool_state.token_0_mint) {
+                false
+            } else {
+                return Err(anyhow!(
+                    "Mints {} and {} do not match pool {}",
+                    quote_params.input_mint,
+                    quote_params.output_mint,
+                    self.key
+                ));
+            };
+
+        let TokenMints {
+            token0_transfer_fee_config,
+            token1_transfer_fee_config,
+            non_transferable,
+            ..
+        } = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .ok_or(anyhow!("Missing token mints and token programs"))?;
+        if *non_transferable {
+            return Err(anyhow!("Mint is non-transferable"));
+        }
+        if self.accounting_inconsistent {
+            return Err(anyhow!(
+                "Pool accounting inconsistent, accrued fees exceed the vault balance"
+            ));
+        }
+
+        let token_mint_0_transfer_fee_config = token0_transfer_fee_config.as_ref();
+        let token_mint_1_transfer_fee_config = token1_transfer_fee_config.as_ref();
+
+        let (source_mint_transfer_fee_config, destination_mint_transfer_fee_config) =
+            if zero_for_one {
+                (
+                    token_mint_0_transfer_fee_config,
+                    token_mint_1_transfer_fee_config,
+                )
+            } else {
+                (
+                    token_mint_1_transfer_fee_config,
+                    token_mint_0_transfer_fee_config,
+                )
+            };
+        // A net input has paid the source transfer fee already
+        let source_mint_transfer_fee_config =
+            source_mint_transfer_fee_config.filter(|_| !net_input);
+
+        let amount = quote_params.amount;
+
+        // Calculate the trade amounts
+        let (total_token_0_amount, total_token_1_amount) = self.tradable_reserves()?;
+        let (total_input_token_amount, total_output_token_amount) = if zero_for_one {
+            (total_token_0_amount, total_token_1_amount)
+        } else {
+            (total_token_1_amount, total_token_0_amount)
+        };
+        if total_input_token_amount == 0 || total_output_token_amount == 0 {
+            return Err(SegaQuoteError::EmptyReserve.into());
+        }
+
+        let (in_amount, amount_out, actual_amount_out, swap_result) = match quote_params.swap_mode {
+            SwapMode::ExactIn => {
+                let actual_amount_in = amount.saturating_sub(get_transfer_fee(
+                    source_mint_transfer_fee_config,
+                    epoch,
+                    amount,
+                )?);
+                if actual_amount_in == 0 {
+                    return Err(SegaQuoteError::AmountTooLow.into());
+                }
+
+                let swap_result = match self.pool_state.curve_type()? {
+                    CurveType::ConstantProduct => CurveCalculator::swap_base_input(
+                        u128::from(actual_amount_in),
+                        total_input_token_amount.into(),
+                        total_output_token_amount.into(),
+                        amm_config.trade_fee_rate,
+                        amm_config.protocol_fee_rate,
+                        amm_config.fund_fee_rate,
+                    ),
+                }
+                .ok_or(SegaQuoteError::CurveFailure)?;
+
+                let amount_out =
+                    curve_amount(swap_result.destination_amount_swapped, "Amount out")?;
+                let actual_amount_out = amount_out.saturating_sub(get_transfer_fee(
+                    destination_mint_transfer_fee_config,
+                    epoch,
+                    amount_out,
+                )?);
+
+                // The curve takes the whole input, reporting more than was requested is a bug
+                let in_amount = curve_amount(swap_result.source_amount_swapped, "Amount in")?;
+                debug_assert_eq!(in_amount, actual_amount_in);
+                if in_amount > amount {
+                    return Err(anyhow!(
+                        "Amount in {} exceeds the requested {}",
+                        in_amount,
+                        amount
+                    ));
+                }
+
+                (in_amount, amount_out, actual_amount_out, swap_result)
+            }
+            SwapMode::ExactOut => {
+                // The requested amount is what the user receives, so the curve has to deliver
+                // it grossed up by the destination transfer fee
+                let amount_out = amount
+                    .checked_add(get_transfer_inverse_fee(
+                        destination_mint_transfer_fee_config,
+                        epoch,
+                        amount,
+                    )?)
+                    .ok_or(SegaQuoteError::OutputUnreachable)?;
+                if amount_out == 0 {
+                    return Err(SegaQuoteError::AmountTooLow.into());
+                }
+                // No input can take the whole reserve out of a constant product pool
+                if amount_out >= total_output_token_amount {
+                    return Err(SegaQuoteError::OutputUnreachable.into());
+                }
+
+                let swap_result = match self.pool_state.curve_type()? {
+                    CurveType::ConstantProduct => CurveCalculator::swap_base_output(
+                        u128::from(amount_out),
+                        total_input_token_amount.into(),
+                        total_output_token_amount.into(),
+                        amm_config.trade_fee_rate,
+                        amm_config.protocol_fee_rate,
+                        amm_config.fund_fee_rate,
+                    ),
+                }
+                .ok_or(SegaQuoteError::CurveFailure)?;
+
+                let source_amount_swapped =
+                    curve_amount(swap_result.source_amount_swapped, "Amount in")?;
+                // The curve rounds a tiny enough output down to a free one, which the program rejects
+                if source_amount_swapped == 0 {
+                    return Err(SegaQuoteError::AmountTooLow.into());
+                }
+                let amount_in = source_amount_swapped
+                    .checked_add(get_transfer_inverse_fee(
+                        source_mint_transfer_fee_config,
+                        epoch,
+                        source_amount_swapped,
+                    )?)
+                    .ok_or(SegaQuoteError::AmountOverflow)
+                    .context("Amount in overflow")?;
+                let actual_amount_out = amount_out.saturating_sub(get_transfer_fee(
+                    destination_mint_transfer_fee_config,
+                    epoch,
+                    amount_out,
+                )?);
+
+                (amount_in, amount_out, actual_amount_out, swap_result)
+            }
+        };
+
+        // The trade fee is taken on the input side, so express it as a fraction of what entered the curve
+        let fee_amount = curve_amount(swap_result.trade_fee, "Fee amount")?;
+        let source_amount_swapped = curve_amount(swap_result.source_amount_swapped, "Amount in")?;
+        let fee_pct = fee_pct(fee_amount, source_amount_swapped);
+
+        // Price impact is measured on what the trader actually pays and receives, transfer fees included
+        let amount_paid = match quote_params.swap_mode {
+            SwapMode::ExactIn => amount,
+            SwapMode::ExactOut => in_amount,
+        };
+        let price_impact_pct = price_impact(
+            amount_paid,
+            actual_amount_out,
+            total_input_token_amount,
+            total_output_token_amount,
+        )
+        .context("Price impact calculation failure")?;
+        if self
+            .max_price_impact_bps
+            .is_some_and(|max_price_impact_bps| {
+                price_impact_pct > Decimal::new(max_price_impact_bps.into(), 4)
+            })
+        {
+            return Err(SegaQuoteError::PriceImpactTooHigh.into());
+        }
+
+        Ok(SegaQuote {
+            quote: Quote {
+                in_amount,
+                out_amount: actual_amount_out,
+                fee_mint: quote_params.input_mint,
+                fee_amount,
+                fee_pct,
+                ..Default::default()
+            },
+            gross_out_amount: amount_out,
+            price_impact_pct,
+            fee_breakdown: FeeBreakdown {
+                trade_fee: fee_amount,
+                protocol_fee: curve_amount(swap_result.protocol_fee, "Protocol fee")?,
+                fund_fee: curve_amount(swap_result.fund_fee, "Fund fee")?,
+            },
+            partial_fill: false,
         })
     }
 
-    fn clone_amm(&self) -> Box<dyn Amm + Send + Sync> {
-        Box::new(self.clone())
+    /// [`Amm::get_swap_and_account_metas`] with each account named after its [`SegaSwap`] field,
+    /// to line them up with those of a failed transaction
+    pub fn labeled_account_metas(&self, params: &SwapParams) -> Result<Vec<(String, AccountMeta)>> {
+        let SwapAndAccountMetas { account_metas, .. } = self.get_swap_and_account_metas(params)?;
+        // The transfer hook accounts follow, a program, its extra account metas and the extra
+        // accounts listed there, for the input then the output mint
+        let output_mint = if params.source_mint == self.pool_state.token_0_mint {
+            self.pool_state.token_1_mint
+        } else {
+            self.pool_state.token_0_mint
+        };
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .context("Missing token mints and token programs")?;
+        let transfer_hook_labels = [params.source_mint, output_mint]
+            .into_iter()
+            .flat_map(|mint| {
+                ["transfer_hook_program", "extra_account_metas"]
+                    .into_iter()
+                    .chain(std::iter::repeat("transfer_hook_extra_account"))
+                    .take(token_mints.transfer_hook_accounts_len(&mint))
+            });
+        let labels = SegaSwap::ACCOUNT_NAMES
+            .into_iter()
+            .chain(transfer_hook_labels);
+        Ok(labels.map(String::from).zip(account_metas).collect())
+    }
+
+    /// Standalone `swap_base_input` instruction for `params.in_amount`, signed by
+    /// `params.token_transfer_authority`, to submit without going through Jupiter
+    pub fn swap_instruction(
+        &self,
+        params: &SwapParams,
+        minimum_amount_out: u64,
+    ) -> Result<Instruction> {
+        if matches!(params.swap_mode, SwapMode::ExactOut) {
+            return Err(anyhow!("Only exact in swaps are supported"));
+        }
+        let SwapAndAccountMetas { account_metas, .. } = self.get_swap_and_account_metas(params)?;
+
+        // The leading program account is only there for the Jupiter program
+        let mut accounts = account_metas[1..].to_vec();
+        accounts[0].is_signer = true;
+        Ok(Instruction {
+            program_id: self.program_id,
+            accounts,
+            data: SegaSwap::swap_base_input_data(params.in_amount, minimum_amount_out),
+        })
+    }
+
+    /// Quote along with the protocol and fund portions of its trade fee
+    pub fn quote_with_fee_breakdown(&self, params: &QuoteParams) -> Result<(Quote, FeeBreakdown)> {
+        let SegaQuote {
+            quote,
+            fee_breakdown,
+            ..
+        } = self.quote_detailed(params)?;
+        Ok((quote, fee_breakdown))
+    }
+
+    /// Same as [`Amm::quote`], with the curve charging the given rates instead of those of the
+    /// pool config, to model a fee change. Reserves and transfer fees are the actual ones
+    pub fn quote_with_fee_override(
+        &self,
+        params: &QuoteParams,
+        trade_fee_rate: u64,
+        protocol_fee_rate: u64,
+        fund_fee_rate: u64,
+    ) -> Result<Quote> {
+        // Same bounds as the program enforces on its configs
+        if trade_fee_rate >= FEE_RATE_DENOMINATOR_VALUE
+            || protocol_fee_rate.saturating_add(fund_fee_rate) > FEE_RATE_DENOMINATOR_VALUE
+        {
+            return Err(anyhow!(
+                "Fee rates {}, {} and {} exceed FEE_RATE_DENOMINATOR_VALUE",
+                trade_fee_rate,
+                protocol_fee_rate,
+                fund_fee_rate
+            ));
+        }
+        let amm_config = self.amm_config.as_ref().context("Missing AmmConfig")?;
+        let overridden_amm = SegaAmm {
+            amm_config: Some(AmmConfig {
+                trade_fee_rate,
+                protocol_fee_rate,
+                fund_fee_rate,
+                ..amm_config.clone()
+            }),
+            ..self.clone()
+        };
+        overridden_amm.quote(params)
+    }
+
+    /// Same as [`Amm::quote`] for exact in quotes, with `partner_fee_bps` of the output going to an
+    /// integrator. The partner fee is added to `fee_amount`, converted to the input mint at the
+    /// execution price. `fee_pct` stays the trade fee rate, its fraction being of the input that
+    /// entered the curve rather than of `in_amount`
+    pub fn quote_with_partner_fee(
+        &self,
+        params: &QuoteParams,
+        partner_fee_bps: u16,
+    ) -> Result<Quote> {
+        if matches!(params.swap_mode, SwapMode::ExactOut) {
+            return Err(anyhow!("Only exact in quotes support a partner fee"));
+        }
+        if partner_fee_bps > 10_000 {
+            return Err(anyhow!(
+                "Partner fee of {} bps exceeds 100%",
+                partner_fee_bps
+            ));
+        }
+        let quote = self.quote(params)?;
+
+        // Cannot exceed `out_amount`, the conversions back never fail
+        let partner_fee =
+            (u128::from(quote.out_amount) * u128::from(partner_fee_bps) / 10_000) as u64;
+        let input_partner_fee = (u128::from(partner_fee) * u128::from(quote.in_amount))
+            .checked_div(quote.out_amount.into())
+            .unwrap_or_default() as u64;
+        Ok(Quote {
+            out_amount: quote.out_amount - partner_fee,
+            fee_amount: quote
+                .fee_amount
+                .checked_add(input_partner_fee)
+                .context("Fee amount overflow")?,
+            ..quote
+        })
+    }
+
+    /// Same as [`Amm::quote`], with the trade fee taken on the input converted to the output mint
+    /// at the execution price of the quote
+    pub fn quote_with_output_fee(&self, params: &QuoteParams) -> Result<Quote> {
+        let quote = self.quote(params)?;
+        let fee_amount = u128::from(quote.fee_amount)
+            .checked_mul(quote.out_amount.into())
+            .and_then(|fee_amount| fee_amount.checked_div(quote.in_amount.into()))
+            .context("Empty quote")?;
+        Ok(Quote {
+            fee_mint: params.output_mint,
+            // The fee is part of the input, so its conversion cannot exceed `out_amount`
+            fee_amount: fee_amount as u64,
+            ..quote
+        })
     }
 }
 
+impl std::fmt::Debug for SegaAmm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SegaAmm")
+            .field("key", &self.key)
+            .field("program_id", &self.program_id)
+            .field("token_0_mint", &self.pool_state.token_0_mint)
+            .field("token_1_mint", &self.pool_state.token_1_mint)
+            .field("vault_0", &self.vault_0)
+            .field("vault_1", &self.vault_1)
+            .field("fee_rates", &self.fee_rates().ok())
+            .field("is_tradable", &self.is_tradable())
+            .field("accounting_inconsistent", &self.accounting_inconsistent)
+            .field("token_mints", &self.token_mints_and_token_programs)
+            .finish_non_exhaustive()
+    }
+}
 
-// We are extracting this here to avoid the need to fix the contract it self.
-// https://github.com/raydium-io/raydium-cp-swap/blob/master/programs/cp-swap/src/states/pool.rs#L139-L148
-fn vault_amount_without_fee(
-    pool: &PoolState,
-    vault_0: u64,
-    vault_1: u64,
-) -> (Option<u64>, Option<u64>) {
-    (
-        vault_0.checked_sub(pool.protocol_fees_token_0 + pool.fund_fees_token_0),
-        vault_1.checked_sub(pool.protocol_fees_token_1 + pool.fund_fees_token_1),
-    )
-}
\ No newline at end of file
+impl std::fmt::Display for SegaAmm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SEGA {} {}/{}",
+            self.key, self.pool_state.token_0_mint, self.pool_state.token_1_mint
+        )
+    }
+}
+
+// Pool states decoded by `update` on the current thread, telling the tests which updates were skipped
+#[cfg(test)]
+thread_local! {
+    static UPDATE_DECODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+impl Amm for SegaAmm {
+    fn from_keyed_account(keyed_account: &KeyedAccount, amm_context: &AmmContext) -> Result<Self> {
+        if !SEGA_SWAP_PROGRAMS.contains_key(&keyed_account.account.owner) {
+            return Err(anyhow!(
+                "Pool {} is owned by {}, not a Sega program",
+                keyed_account.key,
+                keyed_account.account.owner
+            ));
+        }
+        let pool_state = PoolState::try_deserialize(&mut keyed_account.account.data.as_ref())?;
+        let authority = derive_authority(pool_state.auth_bump, &keyed_account.account.owner)?;
+
+        Ok(Self {
+            key: keyed_account.key,
+            pool_state,
+            amm_config: None,
+            vault_0: VaultState::Missing,
+            vault_1: VaultState::Missing,
+            token_mints_and_token_programs: None,
+            observation_state: None,
+            authority,
+            accounting_inconsistent: false,
+            vaults_require_memo: (false, false),
+            quote_before_open_time: false,
+            partial_fill: false,
+            max_price_impact_bps: None,
+            quote_observer: None,
+            amm_config_cache: None,
+            update_fingerprint: None,
+            epoch: amm_context.clock_ref.epoch.clone(),
+            timestamp: amm_context.clock_ref.unix_timestamp.clone(),
+            program_id: keyed_account.account.owner,
+        })
+    }
+
+    fn label(&self) -> String {
+        "SEGA".into()
+    }
+
+    fn program_id(&self) -> Pubkey {
+        self.program_id
+    }
+
+    fn key(&self) -> Pubkey {
+        self.key
+    }
+
+    fn get_reserve_mints(&self) -> Vec<Pubkey> {
+        vec![self.pool_state.token_0_mint, self.pool_state.token_1_mint]
+    }
+
+    fn get_accounts_to_update(&self) -> Vec<Pubkey> {
+        let mut keys = vec![
+            self.key,
+            self.pool_state.token_0_vault,
+            self.pool_state.token_1_vault,
+            self.pool_state.amm_config,
+        ];
+        keys.extend([self.pool_state.token_0_mint, self.pool_state.token_1_mint]);
+        keys.push(self.pool_state.observation_key);
+        // Known once the mints are, so they are fetched from the update after the first one
+        if let Some(token_mints) = self.token_mints_and_token_programs.as_deref() {
+            keys.extend(
+                token_mints
+                    .transfer_hooks()
+                    .map(|(_, _, extra_account_metas)| extra_account_metas),
+            );
+        }
+        keys
+    }
+
+    // Everything is decoded before being stored, so that a failed update never leaves
+    // reserves read next to the fee fields of a different pool state
+    fn update(&mut self, account_map: &AccountMap) -> Result<()> {
+        // The accounts other than the pool are those it references, so they are the same
+        // as long as the pool data is
+        let mut hasher = DefaultHasher::new();
+        for key in self.get_accounts_to_update() {
+            account_map
+                .get(&key)
+                .map(|account| (account.owner, &account.data))
+                .hash(&mut hasher);
+        }
+        let update_fingerprint = hasher.finish();
+        if self.update_fingerprint == Some(update_fingerprint) {
+            return Ok(());
+        }
+
+        let pool_state_data = try_get_account_data(account_map, &self.key)?;
+        let pool_state = PoolState::try_deserialize(&mut pool_state_data.as_ref())?;
+        #[cfg(test)]
+        UPDATE_DECODES.with(|decodes| decodes.set(decodes.get() + 1));
+        let authority = if pool_state.auth_bump != self.pool_state.auth_bump {
+            derive_authority(pool_state.auth_bump, &self.program_id)?
+        } else {
+            self.authority
+        };
+
+        let token0_mint = decode_mint(
+            account_map,
+            &pool_state.token_0_mint,
+            &pool_state.token_0_program,
+            0,
+        )?;
+        let token1_mint = decode_mint(
+            account_map,
+            &pool_state.token_1_mint,
+            &pool_state.token_1_program,
+            1,
+        )?;
+
+        // A config missing from the map is more likely a fetch hiccup than a closed account,
+        // so the one already decoded for the same key is kept
+        let amm_config = match try_get_account_data(account_map, &pool_state.amm_config) {
+            Ok(amm_config_data) => match &self.amm_config_cache {
+                Some(amm_config_cache) => decode_cached_amm_config(
+                    amm_config_cache,
+                    &pool_state.amm_config,
+                    amm_config_data,
+                )?,
+                None => AmmConfig::try_deserialize(&mut amm_config_data.as_ref())?,
+            },
+            Err(error) => match &self.amm_config {
+                Some(amm_config) if pool_state.amm_config == self.pool_state.amm_config => {
+                    amm_config.clone()
+                }
+                _ => return Err(error),
+            },
+        };
+
+        // A validation account owned by another program is none the hook would read
+        let mut token_mints = TokenMints::new(&pool_state, token0_mint, token1_mint);
+        let extra_accounts = token_mints
+            .transfer_hooks()
+            .map(|(mint, program_id, extra_account_metas)| {
+                account_map
+                    .get(&extra_account_metas)
+                    .filter(|account| account.owner == program_id)
+                    .map(|account| decode_extra_account_metas(&account.data))
+                    .transpose()
+                    .with_context(|| {
+                        format!(
+                            "Invalid transfer hook validation account {} of mint {}",
+                            extra_account_metas, mint
+                        )
+                    })
+                    .map(|extra_accounts| (mint, extra_accounts))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (mint, extra_accounts) in extra_accounts {
+            if mint == token_mints.token0 {
+                token_mints.token0_transfer_hook_extra_accounts = extra_accounts;
+            } else {
+                token_mints.token1_transfer_hook_extra_accounts = extra_accounts;
+            }
+        }
+
+        self.update_vaults(account_map, &pool_state);
+
+        // The oracle is not needed to quote, a missing observation account only disables the TWAP readers.
+        // One owned by another program could hold any prices, it is treated as missing
+        self.observation_state = match account_map.get(&pool_state.observation_key) {
+            Some(account) if account.owner != self.program_id => {
+                log::warn!(
+                    "Ignoring observation account {} of pool {}, owned by {} instead of {}",
+                    pool_state.observation_key,
+                    self.key,
+                    account.owner,
+                    self.program_id
+                );
+                None
+            }
+            account => account.and_then(|account| decode_observation(&account.data).ok()),
+        }
+        .map(Arc::new);
+
+        self.token_mints_and_token_programs = Some(Arc::new(token_mints));
+        self.amm_config = Some(amm_config);
+        self.authority = authority;
+        self.pool_state = pool_state;
+        self.update_fingerprint = Some(update_fingerprint);
+
+        Ok(())
+    }
+
+    fn quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        Ok(self.quote_detailed(quote_params)?.quote)
+    }
+
+    fn supports_exact_out(&self) -> bool {
+        self.amm_config.is_some() && self.pool_state.get_status_by_bit(PoolStatusBitIndex::Swap)
+    }
+
+    // Transfer hook accounts depend on the mints, so the metas have to be rebuilt once they load
+    fn has_dynamic_accounts(&self) -> bool {
+        self.token_mints_and_token_programs
+            .as_deref()
+            .is_some_and(|token_mints| {
+                token_mints.token0_transfer_hook_program.is_some()
+                    || token_mints.token1_transfer_hook_program.is_some()
+            })
+    }
+
+    // `AmmUserSetup` has no variant for wrapping SOL, the router wraps and unwraps around the
+    // whole route. `SegaAmm::uses_wrapped_sol` tells integrators when that is needed.
+    // There is no memo variant either, see `SegaAmm::requires_memo`
+    fn get_user_setup(&self) -> Option<AmmUserSetup> {
+        None
+    }
+
+    fn get_accounts_len(&self) -> usize {
+        let Some(token_mints) = self.token_mints_and_token_programs.as_deref() else {
+            return SegaSwap::ACCOUNTS_LEN;
+        };
+        SegaSwap::ACCOUNTS_LEN
+            + token_mints.transfer_hook_accounts_len(&token_mints.token0)
+            + token_mints.transfer_hook_accounts_len(&token_mints.token1)
+    }
+
+    fn get_swap_and_account_metas(&self, swap_params: &SwapParams) -> Result<SwapAndAccountMetas> {
+        if self.token_mints_and_token_programs.is_none() {
+            return Err(anyhow!("Missing token mints and token programs"));
+        }
+
+        let token_mints = self
+            .token_mints_and_token_programs
+            .as_deref()
+            .ok_or(anyhow!("Missing token mints and token programs"))?;
+        let TokenMints {
+            token0_program: token_0_token_program,
+            token1_program: token_1_token_program,
+            ..
+        } = token_mints;
+
+        let (
+            input_token_program,
+            input_vault,
+            input_token_mint,
+            output_token_program,
+            output_vault,
+            output_token_mint,
+        ) = if swap_params.source_mint == self.pool_state.token_0_mint {
+            (
+                *token_0_token_program,
+                self.pool_state.token_0_vault,
+                self.pool_state.token_0_mint,
+                *token_1_token_program,
+                self.pool_state.token_1_vault,
+                self.pool_state.token_1_mint,
+            )
+        } else if swap_params.source_mint == self.pool_state.token_1_mint {
+            (
+                *token_1_token_program,
+                self.pool_state.token_1_vault,
+                self.pool_state.token_1_mint,
+                *token_0_token_program,
+                self.pool_state.token_0_vault,
+                self.pool_state.token_0_mint,
+            )
+        } else {
+            return Err(anyhow!(
+                "Mint {} is not in pool {}",
+                swap_params.source_mint,
+                self.key
+            ));
+        };
+
+        let mut account_metas = SegaSwap {
+            program: self.program_id,
+            payer: swap_params.token_transfer_authority,
+            authority: self.get_authority(),
+            amm_config: self.pool_state.amm_config,
+            pool_state: self.key,
+            input_token_account: swap_params.source_token_account,
+            output_token_account: swap_params.destination_token_account,
+            input_vault,
+            output_vault,
+            input_token_program,
+            output_token_program,
+            input_token_mint,
+            output_token_mint,
+            observation_state: self.pool_state.observation_key,
+        }
+        .to_account_metas(None);
+        debug_assert_eq!(account_metas.len(), SegaSwap::ACCOUNTS_LEN);
+        // Forwarded as remaining accounts for the token-2022 transfer CPIs, from the user to the
+        // input vault then from the output vault to the user
+        for (mint, source, destination, authority, amount) in [
+            (
+                input_token_mint,
+                swap_params.source_token_account,
+                input_vault,
+                swap_params.token_transfer_authority,
+                swap_params.in_amount,
+            ),
+            (
+                output_token_mint,
+                output_vault,
+                swap_params.destination_token_account,
+                self.get_authority(),
+                swap_params.out_amount,
+            ),
+        ] {
+            account_metas.extend(token_mints.transfer_hook_account_metas(
+                mint,
+                source,
+                destination,
+                authority,
+                amount,
+            )?);
+        }
+
+        Ok(SwapAndAccountMetas {
+            swap: Swap::RaydiumCP,
+            account_metas,
+        })
+    }
+
+    fn clone_amm(&self) -> Box<dyn Amm + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+// Frozen or missing vaults leave nothing to read, as they leave nothing to quote
+impl ReserveQuery for SegaAmm {
+    fn reserves(&self) -> Result<Vec<u128>> {
+        let (reserve_0, reserve_1) = self.tradable_reserves()?;
+        Ok(vec![reserve_0.into(), reserve_1.into()])
+    }
+}
+
+/// Smallest `out_amount` of `quote` still accepted with `slippage_bps` of slippage,
+/// slippage above 100% accepts any amount
+pub fn min_amount_out(quote: &Quote, slippage_bps: u16) -> u64 {
+    let remaining_bps = 10_000u128.saturating_sub(slippage_bps.into());
+    // Cannot exceed `out_amount`, the conversion back never fails
+    (u128::from(quote.out_amount) * remaining_bps / 10_000) as u64
+}
+
+/// Spot price of `base_mint` across `pools` of the same pair, each weighted by its `base_mint`
+/// reserve. Pools not tradable, or not loaded, are left out
+pub fn weighted_mid_price(pools: &[&SegaAmm], base_mint: &Pubkey) -> Result<Decimal> {
+    let mut weighted_price_sum = Decimal::ZERO;
+    let mut base_reserve_sum = Decimal::ZERO;
+    for pool in pools {
+        if !pool.get_reserve_mints().contains(base_mint) {
+            return Err(anyhow!(
+                "Mint {} is not part of pool {}",
+                base_mint,
+                pool.key
+            ));
+        }
+        if !pool.is_tradable() {
+            continue;
+        }
+        let (Ok(spot_price), Ok((total_token_0_amount, total_token_1_amount))) =
+            (pool.spot_price(base_mint), pool.tradable_reserves())
+        else {
+            continue;
+        };
+        let base_reserve = Decimal::from(if *base_mint == pool.pool_state.token_0_mint {
+            total_token_0_amount
+        } else {
+            total_token_1_amount
+        });
+
+        weighted_price_sum = spot_price
+            .checked_mul(base_reserve)
+            .and_then(|weighted_price| weighted_price_sum.checked_add(weighted_price))
+            .context("Weighted price overflow")?;
+        base_reserve_sum = base_reserve_sum
+            .checked_add(base_reserve)
+            .context("Base reserve overflow")?;
+    }
+
+    weighted_price_sum
+        .checked_div(base_reserve_sum)
+        .context("No tradable pool")
+}
+
+// We are extracting this here to avoid the need to fix the contract it self.
+// https://github.com/raydium-io/raydium-cp-swap/blob/master/programs/cp-swap/src/states/pool.rs#L139-L148
+fn vault_amount_without_fee(
+    pool: &PoolState,
+    vault_0: u64,
+    vault_1: u64,
+) -> (Option<u64>, Option<u64>) {
+    (
+        pool.protocol_fees_token_0
+            .checked_add(pool.fund_fees_token_0)
+            .and_then(|fees_token_0| vault_0.checked_sub(fees_token_0)),
+        pool.protocol_fees_token_1
+            .checked_add(pool.fund_fees_token_1)
+            .and_then(|fees_token_1| vault_1.checked_sub(fees_token_1)),
+    )
+}
+
+/// Vaults which balance is unknown are not flagged
+fn accrued_fees_exceed_vaults(
+    pool_state: &PoolState,
+    vault_0: VaultState,
+    vault_1: VaultState,
+) -> bool {
+    let (vault_0_amount, vault_1_amount) = vault_amount_without_fee(
+        pool_state,
+        vault_0.amount(0).unwrap_or(u64::MAX),
+        vault_1.amount(1).unwrap_or(u64::MAX),
+    );
+    vault_0_amount.is_none() || vault_1_amount.is_none()
+}
+
+/// Token `index` mint of the pool, which has to be owned by the `token_program` the pool records
+/// for its side. Either side may use either program, but the swap passes the recorded one
+fn decode_mint(
+    account_map: &AccountMap,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    index: u8,
+) -> Result<StateWithExtensionsOwned<Mint>> {
+    let account = account_map
+        .get(mint)
+        .with_context(|| format!("Token {index} mint not found"))?;
+    if account.owner != *token_program {
+        return Err(anyhow!(
+            "Token {} mint {} is owned by {} instead of {}",
+            index,
+            mint,
+            account.owner,
+            token_program
+        ));
+    }
+    StateWithExtensionsOwned::<Mint>::unpack(account.data.to_vec())
+        .ok()
+        .with_context(|| format!("Token {index} mint not found"))
+}
+
+/// Only decodes `amm_config_data` when it differs from what the cache holds for `amm_config`
+fn decode_cached_amm_config(
+    amm_config_cache: &AmmConfigCache,
+    amm_config: &Pubkey,
+    amm_config_data: &[u8],
+) -> Result<AmmConfig> {
+    if let Some((cached_amm_config_data, cached_amm_config)) =
+        amm_config_cache.read().unwrap().get(amm_config)
+    {
+        if cached_amm_config_data.as_slice() == amm_config_data {
+            return Ok(cached_amm_config.clone());
+        }
+    }
+
+    let decoded_amm_config = AmmConfig::try_deserialize(&mut amm_config_data.as_ref())?;
+    amm_config_cache.write().unwrap().insert(
+        *amm_config,
+        (amm_config_data.to_vec(), decoded_amm_config.clone()),
+    );
+    Ok(decoded_amm_config)
+}
+
+fn derive_authority(auth_bump: u8, program_id: &Pubkey) -> Result<Pubkey> {
+    Pubkey::create_program_address(&[AUTH_SEED.as_bytes(), &[auth_bump]], program_id)
+        .context("Invalid authority bump")
+}
+
+fn transfer_fee_config(mint: &StateWithExtensionsOwned<Mint>) -> Option<TransferFeeConfig> {
+    mint.get_extension::<TransferFeeConfig>().ok().copied()
+}
+
+/// Whether token accounts of `mint` start frozen, the `DefaultAccountState` extension
+fn default_frozen(mint: &StateWithExtensionsOwned<Mint>) -> bool {
+    mint.get_extension::<DefaultAccountState>()
+        .is_ok_and(|default_account_state| {
+            default_account_state.state == AccountState::Frozen as u8
+        })
+}
+
+/// Whether a `PermanentDelegate` extension of `mint` names a delegate, which can then move the
+/// tokens of any account, the pool vaults included
+fn permanent_delegate(mint: &StateWithExtensionsOwned<Mint>) -> bool {
+    mint.get_extension::<PermanentDelegate>()
+        .is_ok_and(|permanent_delegate| {
+            Option::<Pubkey>::from(permanent_delegate.delegate).is_some()
+        })
+}
+
+// `ExtensionType::ScaledUiAmount`, which spl-token-2022 7 predates
+const SCALED_UI_AMOUNT_EXTENSION_TYPE: u16 = 25;
+
+/// Value of the `ScaledUiAmount` mint extension, past its authority
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ScaledUiAmountConfig {
+    multiplier: f64,
+    new_multiplier_effective_timestamp: i64,
+    new_multiplier: f64,
+}
+
+impl ScaledUiAmountConfig {
+    fn multiplier_at(&self, unix_timestamp: i64) -> f64 {
+        if unix_timestamp >= self.new_multiplier_effective_timestamp {
+            self.new_multiplier
+        } else {
+            self.multiplier
+        }
+    }
+}
+
+// The extension is unknown to `ExtensionType`, so its TLV entry is looked up by hand
+fn scaled_ui_amount_config(mint: &StateWithExtensionsOwned<Mint>) -> Option<ScaledUiAmountConfig> {
+    let mut tlv_data = mint.get_tlv_data();
+    while let [type_0, type_1, length_0, length_1, rest @ ..] = tlv_data {
+        let length = usize::from(u16::from_le_bytes([*length_0, *length_1]));
+        let value = rest.get(..length)?;
+        if u16::from_le_bytes([*type_0, *type_1]) == SCALED_UI_AMOUNT_EXTENSION_TYPE {
+            let field = |offset: usize| -> Option<[u8; 8]> {
+                value.get(offset..offset + 8)?.try_into().ok()
+            };
+            return Some(ScaledUiAmountConfig {
+                multiplier: f64::from_le_bytes(field(32)?),
+                new_multiplier_effective_timestamp: i64::from_le_bytes(field(40)?),
+                new_multiplier: f64::from_le_bytes(field(48)?),
+            });
+        }
+        tlv_data = &rest[length..];
+    }
+    None
+}
+
+fn transfer_hook_program_id(mint: &StateWithExtensionsOwned<Mint>) -> Option<Pubkey> {
+    mint.get_extension::<TransferHook>()
+        .ok()
+        .and_then(|transfer_hook| transfer_hook.program_id.into())
+}
+
+// The extra account metas a validation account lists for the execute instruction
+fn decode_extra_account_metas(data: &[u8]) -> Result<Vec<ExtraAccountMeta>> {
+    let tlv_state = TlvStateBorrowed::unpack(data)?;
+    let extra_account_metas =
+        ExtraAccountMetaList::unpack_with_tlv_state::<ExecuteInstruction>(&tlv_state)?;
+    Ok(extra_account_metas.data().to_vec())
+}
+
+// Inverse of `decode_extra_account_metas`
+fn extra_account_metas_data(extra_account_metas: &[ExtraAccountMeta]) -> Result<Vec<u8>> {
+    let mut data = vec![0; ExtraAccountMetaList::size_of(extra_account_metas.len())?];
+    ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, extra_account_metas)?;
+    Ok(data)
+}
+
+// Inverse of `StateWithExtensionsOwned::unpack`, which drops the padding and the account type
+fn mint_data(mint: &StateWithExtensionsOwned<Mint>) -> Vec<u8> {
+    let mut data = vec![0; Mint::LEN];
+    mint.base.pack_into_slice(&mut data);
+    let tlv_data = mint.get_tlv_data();
+    if !tlv_data.is_empty() {
+        data.resize(spl_token_2022::state::Account::LEN, 0);
+        data.push(AccountType::Mint as u8);
+        data.extend_from_slice(tlv_data);
+    }
+    data
+}
+
+fn observation_state_data(observation_state: &ObservationState) -> Vec<u8> {
+    let mut data = ObservationState::DISCRIMINATOR.to_vec();
+    data.extend_from_slice(bytemuck::bytes_of(observation_state));
+    data
+}
+
+/// Decodes an observation account, its Anchor discriminator included
+pub fn decode_observation(data: &[u8]) -> Result<ObservationState> {
+    let discriminator_len = ObservationState::DISCRIMINATOR.len();
+    let observation_state_len = discriminator_len + std::mem::size_of::<ObservationState>();
+    if data.len() < observation_state_len
+        || data[..discriminator_len] != *ObservationState::DISCRIMINATOR
+    {
+        return Err(anyhow!("Invalid observation state account"));
+    }
+
+    bytemuck::try_from_bytes::<ObservationState>(&data[discriminator_len..observation_state_len])
+        .copied()
+        .map_err(|e| anyhow!("Invalid observation state account: {e}"))
+}
+
+fn curve_amount(amount: u128, amount_name: &str) -> Result<u64> {
+    u64::try_from(amount)
+        .map_err(|_| SegaQuoteError::AmountOverflow)
+        .with_context(|| format!("{amount_name} {amount} does not fit in a u64"))
+}
+
+/// Nothing swapped means no fee rather than a division by zero
+fn fee_pct(fee_amount: u64, source_amount_swapped: u64) -> Decimal {
+    Decimal::from(fee_amount)
+        .checked_div(Decimal::from(source_amount_swapped))
+        .unwrap_or(Decimal::ZERO)
+}
+
+fn price_impact(
+    amount_in: u64,
+    amount_out: u64,
+    total_input_token_amount: u64,
+    total_output_token_amount: u64,
+) -> Option<Decimal> {
+    let spot_price = Decimal::from(total_output_token_amount)
+        .checked_div(Decimal::from(total_input_token_amount))?;
+    let execution_price = Decimal::from(amount_out).checked_div(Decimal::from(amount_in))?;
+    Decimal::ONE.checked_sub(execution_price.checked_div(spot_price)?)
+}
+
+fn get_transfer_fee(
+    transfer_fee_config: Option<&TransferFeeConfig>,
+    epoch: u64,
+    pre_fee_amount: u64,
+) -> Result<u64> {
+    match transfer_fee_config {
+        Some(transfer_fee_config) => transfer_fee_config
+            .calculate_epoch_fee(epoch, pre_fee_amount)
+            .context("Fee calculation failure"),
+        None => Ok(0),
+    }
+}
+
+// Mirrors the on-chain `get_transfer_inverse_fee`, a 100% fee rate can only be satisfied by paying the maximum fee
+fn get_transfer_inverse_fee(
+    transfer_fee_config: Option<&TransferFeeConfig>,
+    epoch: u64,
+    post_fee_amount: u64,
+) -> Result<u64> {
+    let Some(transfer_fee_config) = transfer_fee_config else {
+        return Ok(0);
+    };
+    let transfer_fee = transfer_fee_config.get_epoch_fee(epoch);
+    if u16::from(transfer_fee.transfer_fee_basis_points) == MAX_FEE_BASIS_POINTS {
+        Ok(u64::from(transfer_fee.maximum_fee))
+    } else {
+        transfer_fee_config
+            .calculate_inverse_epoch_fee(epoch, post_fee_amount)
+            .context("Fee calculation failure")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use sega_cp_swap::{Observation, OBSERVATION_NUM};
+    use solana_sdk::account::Account;
+    use spl_tlv_account_resolution::seeds::Seed;
+    use spl_token_2022::extension::{
+        memo_transfer::MemoTransfer, transfer_fee::TransferFee, BaseStateWithExtensionsMut,
+        ExtensionType, StateWithExtensionsMut,
+    };
+    use spl_token_2022::state::{Account as TokenAccount, AccountState};
+
+    fn plain_mint_data(decimals: u8) -> Vec<u8> {
+        let mut data = vec![0; Mint::LEN];
+        Mint::pack(
+            Mint {
+                decimals,
+                is_initialized: true,
+                ..Default::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    fn mint_data_with_extensions(
+        decimals: u8,
+        extension_types: &[ExtensionType],
+        init_extensions: impl FnOnce(&mut StateWithExtensionsMut<Mint>),
+    ) -> Vec<u8> {
+        let mut data =
+            vec![0; ExtensionType::try_calculate_account_len::<Mint>(extension_types).unwrap()];
+        {
+            let mut state =
+                StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+            init_extensions(&mut state);
+            state.base = Mint {
+                decimals,
+                is_initialized: true,
+                ..Default::default()
+            };
+            state.pack_base();
+            state.init_account_type().unwrap();
+        }
+        data
+    }
+
+    fn transfer_hook_mint_data(decimals: u8, program_id: Pubkey) -> Vec<u8> {
+        mint_data_with_extensions(decimals, &[ExtensionType::TransferHook], |state| {
+            let transfer_hook = state.init_extension::<TransferHook>(true).unwrap();
+            transfer_hook.program_id = Some(program_id).try_into().unwrap();
+        })
+    }
+
+    fn transfer_fee_mint_data(
+        decimals: u8,
+        transfer_fee_basis_points: u16,
+        maximum_fee: u64,
+    ) -> Vec<u8> {
+        mint_data_with_extensions(decimals, &[ExtensionType::TransferFeeConfig], |state| {
+            let transfer_fee_config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+            let transfer_fee = TransferFee {
+                epoch: 0.into(),
+                maximum_fee: maximum_fee.into(),
+                transfer_fee_basis_points: transfer_fee_basis_points.into(),
+            };
+            transfer_fee_config.older_transfer_fee = transfer_fee;
+            transfer_fee_config.newer_transfer_fee = transfer_fee;
+        })
+    }
+
+    fn plain_mint(decimals: u8) -> StateWithExtensionsOwned<Mint> {
+        StateWithExtensionsOwned::<Mint>::unpack(plain_mint_data(decimals)).unwrap()
+    }
+
+    fn test_amm(vault_0_amount: u64, vault_1_amount: u64, trade_fee_rate: u64) -> SegaAmm {
+        let (_, auth_bump) =
+            Pubkey::find_program_address(&[AUTH_SEED.as_bytes()], &sega_swap_programs::SEGA);
+        let pool_state = PoolState {
+            amm_config: Pubkey::new_unique(),
+            token_0_vault: Pubkey::new_unique(),
+            token_1_vault: Pubkey::new_unique(),
+            token_0_mint: Pubkey::new_unique(),
+            token_1_mint: Pubkey::new_unique(),
+            token_0_program: spl_token::ID,
+            token_1_program: spl_token::ID,
+            observation_key: Pubkey::new_unique(),
+            auth_bump,
+            ..Default::default()
+        };
+        SegaAmm::from_parts(
+            Pubkey::new_unique(),
+            sega_swap_programs::SEGA,
+            pool_state,
+            AmmConfig {
+                trade_fee_rate,
+                ..Default::default()
+            },
+            vault_0_amount,
+            vault_1_amount,
+            plain_mint(9),
+            plain_mint(6),
+            0,
+            0,
+        )
+        .unwrap()
+    }
+
+    fn account(data: Vec<u8>, owner: Pubkey) -> Account {
+        Account {
+            data,
+            owner,
+            ..Account::default()
+        }
+    }
+
+    fn token_account_data(mint: Pubkey, amount: u64, state: AccountState) -> Vec<u8> {
+        let mut data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint,
+                amount,
+                state,
+                ..Default::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    fn memo_token_account_data(mint: Pubkey, amount: u64) -> Vec<u8> {
+        let mut data = vec![
+            0;
+            ExtensionType::try_calculate_account_len::<TokenAccount>(&[
+                ExtensionType::MemoTransfer
+            ])
+            .unwrap()
+        ];
+        {
+            let mut state =
+                StateWithExtensionsMut::<TokenAccount>::unpack_uninitialized(&mut data).unwrap();
+            let memo_transfer = state.init_extension::<MemoTransfer>(true).unwrap();
+            memo_transfer.require_incoming_transfer_memos = true.into();
+            state.base = TokenAccount {
+                mint,
+                amount,
+                state: AccountState::Initialized,
+                ..Default::default()
+            };
+            state.pack_base();
+            state.init_account_type().unwrap();
+        }
+        data
+    }
+
+    /// Serializes what `amm` holds back into the accounts `update` reads
+    fn test_account_map(amm: &SegaAmm, mint_0_data: Vec<u8>, mint_1_data: Vec<u8>) -> AccountMap {
+        let pool_state = &amm.pool_state;
+        let mut pool_state_data = Vec::new();
+        pool_state.try_serialize(&mut pool_state_data).unwrap();
+        let mut amm_config_data = Vec::new();
+        amm.amm_config
+            .as_ref()
+            .unwrap()
+            .try_serialize(&mut amm_config_data)
+            .unwrap();
+
+        let mut account_map = AccountMap::default();
+        account_map.insert(amm.key, account(pool_state_data, amm.program_id));
+        account_map.insert(
+            pool_state.amm_config,
+            account(amm_config_data, amm.program_id),
+        );
+        account_map.insert(
+            pool_state.token_0_mint,
+            account(mint_0_data, pool_state.token_0_program),
+        );
+        account_map.insert(
+            pool_state.token_1_mint,
+            account(mint_1_data, pool_state.token_1_program),
+        );
+        for (vault, mint, token_program, vault_state) in [
+            (
+                pool_state.token_0_vault,
+                pool_state.token_0_mint,
+                pool_state.token_0_program,
+                amm.vault_0,
+            ),
+            (
+                pool_state.token_1_vault,
+                pool_state.token_1_mint,
+                pool_state.token_1_program,
+                amm.vault_1,
+            ),
+        ] {
+            let token_account_data = match vault_state {
+                VaultState::Amount(amount) => {
+                    token_account_data(mint, amount, AccountState::Initialized)
+                }
+                VaultState::Frozen => token_account_data(mint, 0, AccountState::Frozen),
+                VaultState::WrongMint => {
+                    token_account_data(Pubkey::new_unique(), 0, AccountState::Initialized)
+                }
+                VaultState::Missing => continue,
+            };
+            account_map.insert(vault, account(token_account_data, token_program));
+        }
+        account_map
+    }
+
+    fn amm_with_status(status: u8) -> SegaAmm {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.pool_state.status = status;
+        amm
+    }
+
+    fn swap_params(amm: &SegaAmm) -> SwapParams {
+        SwapParams {
+            swap_mode: SwapMode::ExactIn,
+            in_amount: 1_000_000,
+            out_amount: 0,
+            source_mint: amm.pool_state.token_0_mint,
+            destination_mint: amm.pool_state.token_1_mint,
+            source_token_account: Pubkey::new_unique(),
+            destination_token_account: Pubkey::new_unique(),
+            token_transfer_authority: Pubkey::new_unique(),
+            quote_mint_to_referrer: None,
+            jupiter_program_id: &jupiter::ID,
+            missing_dynamic_accounts_as_default: false,
+        }
+    }
+
+    fn exact_in(amm: &SegaAmm, amount: u64) -> QuoteParams {
+        QuoteParams {
+            amount,
+            input_mint: amm.pool_state.token_0_mint,
+            output_mint: amm.pool_state.token_1_mint,
+            swap_mode: SwapMode::ExactIn,
+        }
+    }
+
+    /// 100 observations 15 seconds apart, wrapping around the ring buffer so that the oldest
+    /// one sits at index 2 and the newest at index 1. The price is 1 up to the last 2 intervals, then 3
+    fn record_price_history(amm: &mut SegaAmm) {
+        let mut observations = [Observation::default(); OBSERVATION_NUM];
+        let mut cumulative_price = 0;
+        for k in 0..OBSERVATION_NUM {
+            if k > 0 {
+                let price = if k >= 98 { 3 } else { 1 };
+                cumulative_price += price * 15;
+            }
+            observations[(2 + k) % OBSERVATION_NUM] = Observation {
+                block_timestamp: 1_000 + 15 * k as u64,
+                cumulative_token_0_price_x32: cumulative_price * Q32,
+                cumulative_token_1_price_x32: 0,
+            };
+        }
+
+        amm.observation_state = Some(Arc::new(ObservationState {
+            initialized: true,
+            observation_index: 1,
+            pool_id: amm.key,
+            observations,
+            padding: [0; 4],
+        }));
+        amm.timestamp
+            .store(2_485, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_from_keyed_account_owner() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+        let mut keyed_account = KeyedAccount {
+            key: amm.key,
+            account: account_map[&amm.key].clone(),
+            params: None,
+        };
+        assert!(SegaAmm::from_keyed_account(&keyed_account, &amm_context).is_ok());
+
+        keyed_account.account.owner = Pubkey::new_unique();
+        assert!(SegaAmm::from_keyed_account(&keyed_account, &amm_context).is_err());
+    }
+
+    #[test]
+    fn test_from_keyed_account_with_clock() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.pool_state.open_time = 100;
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, 100, u64::MAX),
+        );
+        let keyed_account = KeyedAccount {
+            key: amm.key,
+            account: account_map[&amm.key].clone(),
+            params: None,
+        };
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+
+        let mut pinned_amm =
+            SegaAmm::from_keyed_account_with_clock(&keyed_account, 3, 100).unwrap();
+        let mut shared_amm = SegaAmm::from_keyed_account(&keyed_account, &amm_context).unwrap();
+        pinned_amm.update(&account_map).unwrap();
+        shared_amm.update(&account_map).unwrap();
+        amm_context
+            .clock_ref
+            .unix_timestamp
+            .store(50, std::sync::atomic::Ordering::Relaxed);
+
+        let quote_params = exact_in(&pinned_amm, 1_000_000);
+        assert!(shared_amm.quote(&quote_params).is_err());
+        assert_eq!(
+            pinned_amm.quote(&quote_params).unwrap().out_amount,
+            shared_amm
+                .quote_at(&quote_params, 3, 100)
+                .unwrap()
+                .out_amount
+        );
+    }
+
+    #[test]
+    fn test_debug_and_display() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, 100, 5_000),
+        );
+        amm.update(&account_map).unwrap();
+
+        assert_eq!(
+            amm.to_string(),
+            format!(
+                "SEGA {} {}/{}",
+                amm.key, amm.pool_state.token_0_mint, amm.pool_state.token_1_mint
+            )
+        );
+        let debug = format!("{amm:?}");
+        assert!(debug.contains(&amm.key.to_string()));
+        assert!(debug.contains("TransferFeeConfig"));
+        assert!(debug.contains("Amount(1000000000)"));
+    }
+
+    #[test]
+    fn test_supports_exact_out() {
+        assert!(amm_with_status(0).supports_exact_out());
+        // bit2 disables swap
+        assert!(!amm_with_status(1 << 2).supports_exact_out());
+
+        let mut amm = amm_with_status(0);
+        amm.amm_config = None;
+        assert!(!amm.supports_exact_out());
+    }
+
+    #[test]
+    fn test_quote_fee_pct() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote = amm.quote(&exact_in(&amm, 1_000_000)).unwrap();
+
+        assert_eq!(quote.fee_amount, 2500);
+        assert_eq!(quote.fee_pct, Decimal::new(25, 4));
+    }
+
+    #[test]
+    fn test_quote_with_fee_breakdown() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let amm_config = amm.amm_config.as_mut().unwrap();
+        amm_config.protocol_fee_rate = 120_000;
+        amm_config.fund_fee_rate = 40_000;
+
+        let (quote, fee_breakdown) = amm
+            .quote_with_fee_breakdown(&exact_in(&amm, 1_000_000))
+            .unwrap();
+
+        assert_eq!(quote.fee_amount, 2500);
+        assert_eq!(
+            fee_breakdown,
+            FeeBreakdown {
+                trade_fee: 2500,
+                protocol_fee: 300,
+                fund_fee: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn test_reserves_delta() {
+        let previous_amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert_eq!(previous_amm.reserves_delta(&previous_amm), (0, 0));
+
+        // A swap of token 0 for token 1, its protocol fee accruing on the input side
+        let mut amm = previous_amm.clone();
+        amm.vault_0 = VaultState::Amount(1_001_000_000);
+        amm.vault_1 = VaultState::Amount(999_000_000);
+        amm.pool_state.protocol_fees_token_0 = 300;
+        assert_eq!(amm.reserves_delta(&previous_amm), (999_700, -1_000_000));
+        assert_eq!(previous_amm.reserves_delta(&amm), (-999_700, 1_000_000));
+
+        amm.vault_1 = VaultState::Frozen;
+        assert_eq!(amm.reserves_delta(&previous_amm), (999_700, -1_000_000_000));
+    }
+
+    #[test]
+    fn test_quote_with_fee_override() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+
+        let same_fee_quote = amm
+            .quote_with_fee_override(&quote_params, 2500, 0, 0)
+            .unwrap();
+        assert_eq!(same_fee_quote.out_amount, quote.out_amount);
+        assert_eq!(same_fee_quote.fee_amount, quote.fee_amount);
+
+        let higher_fee_quote = amm
+            .quote_with_fee_override(&quote_params, 10_000, 0, 0)
+            .unwrap();
+        assert_eq!(higher_fee_quote.fee_amount, 10_000);
+        assert!(higher_fee_quote.out_amount < quote.out_amount);
+
+        let no_fee_quote = amm.quote_with_fee_override(&quote_params, 0, 0, 0).unwrap();
+        assert_eq!(no_fee_quote.fee_amount, 0);
+        assert!(no_fee_quote.out_amount > quote.out_amount);
+
+        // The pool config is left as is
+        assert_eq!(amm.fee_rates().unwrap(), (2500, 0, 0));
+        assert!(amm
+            .quote_with_fee_override(&quote_params, FEE_RATE_DENOMINATOR_VALUE, 0, 0)
+            .is_err());
+        assert!(amm
+            .quote_with_fee_override(&quote_params, 2500, 600_000, 600_000)
+            .is_err());
+    }
+
+    #[test]
+    fn test_quote_with_partner_fee() {
+        let amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+        let partner_fee_quote = amm.quote_with_partner_fee(&quote_params, 50).unwrap();
+
+        let partner_fee = quote.out_amount * 50 / 10_000;
+        assert!(partner_fee > 0);
+        assert_eq!(partner_fee_quote.out_amount, quote.out_amount - partner_fee);
+        assert_eq!(
+            partner_fee_quote.fee_amount,
+            quote.fee_amount + partner_fee * quote.in_amount / quote.out_amount
+        );
+        assert_eq!(partner_fee_quote.fee_pct, quote.fee_pct);
+        assert_eq!(partner_fee_quote.fee_mint, quote.fee_mint);
+        assert_eq!(partner_fee_quote.in_amount, quote.in_amount);
+
+        assert_eq!(
+            amm.quote_with_partner_fee(&quote_params, 0)
+                .unwrap()
+                .out_amount,
+            quote.out_amount
+        );
+        assert!(amm.quote_with_partner_fee(&quote_params, 10_001).is_err());
+        assert!(amm
+            .quote_with_partner_fee(
+                &QuoteParams {
+                    swap_mode: SwapMode::ExactOut,
+                    ..quote_params
+                },
+                50
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_quote_with_output_fee() {
+        let amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        for quote_params in [
+            exact_in(&amm, 1_000_000),
+            QuoteParams {
+                swap_mode: SwapMode::ExactOut,
+                ..exact_in(&amm, 1_000_000)
+            },
+        ] {
+            let quote = amm.quote(&quote_params).unwrap();
+            let output_fee_quote = amm.quote_with_output_fee(&quote_params).unwrap();
+
+            assert_eq!(quote.fee_mint, quote_params.input_mint);
+            assert_eq!(output_fee_quote.fee_mint, quote_params.output_mint);
+            assert_eq!(
+                output_fee_quote.fee_amount,
+                quote.fee_amount * quote.out_amount / quote.in_amount
+            );
+            assert!(output_fee_quote.fee_amount > quote.fee_amount);
+            assert_eq!(output_fee_quote.out_amount, quote.out_amount);
+            assert_eq!(output_fee_quote.in_amount, quote.in_amount);
+        }
+    }
+
+    #[test]
+    fn test_price_impact_grows_with_size() {
+        let amm = test_amm(1_000_000, 1_000_000, 2500);
+
+        let price_impacts = [1_000, 10_000, 100_000].map(|amount| {
+            amm.quote_detailed(&exact_in(&amm, amount))
+                .unwrap()
+                .price_impact_pct
+        });
+
+        assert!(price_impacts[0] > Decimal::ZERO);
+        assert!(price_impacts[0] < price_impacts[1]);
+        assert!(price_impacts[1] < price_impacts[2]);
+    }
+
+    #[test]
+    fn test_twap_price() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert!(amm.twap_price(30).is_err());
+
+        record_price_history(&mut amm);
+        assert_eq!(amm.twap_price(30).unwrap(), Decimal::from(3));
+        // (97 * 15 * 1 + 2 * 15 * 3) / 1485
+        let full_window_price = amm.twap_price(1_485).unwrap();
+        assert!((full_window_price - Decimal::new(104, 2)).abs() < Decimal::new(1, 3));
+        assert!(amm.twap_price(1_500).is_err());
+    }
+
+    #[test]
+    fn test_quote_with_twap_deviation() {
+        let mut amm = test_amm(1_000_000_000, 3_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let twap_deviation_bps = |amm: &SegaAmm, twap_window_seconds| {
+            let (quote, twap_deviation_bps) = amm
+                .quote_with_twap_deviation(&quote_params, twap_window_seconds)
+                .unwrap();
+            assert_eq!(
+                quote.out_amount,
+                amm.quote(&quote_params).unwrap().out_amount
+            );
+            twap_deviation_bps
+        };
+        assert_eq!(twap_deviation_bps(&amm, 30), None);
+
+        record_price_history(&mut amm);
+        assert_eq!(twap_deviation_bps(&amm, 30), Some(0));
+        assert_eq!(twap_deviation_bps(&amm, 1_500), None);
+
+        amm.vault_1 = VaultState::Amount(3_300_000_000);
+        assert_eq!(twap_deviation_bps(&amm, 30), Some(1_000));
+    }
+
+    #[test]
+    fn test_last_observation_timestamp() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert_eq!(amm.last_observation_timestamp(), None);
+
+        record_price_history(&mut amm);
+        assert_eq!(amm.last_observation_timestamp(), Some(2_485));
+
+        Arc::make_mut(amm.observation_state.as_mut().unwrap()).initialized = false;
+        assert_eq!(amm.last_observation_timestamp(), None);
+    }
+
+    #[test]
+    fn test_quote_with_unset_clock() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        assert!(amm.quote(&quote_params).is_ok());
+
+        amm.pool_state.open_time = 1_000;
+        let error = amm.quote(&quote_params).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SegaQuoteError>(),
+            Some(&SegaQuoteError::ClockUninitialized)
+        );
+        assert_eq!(error.to_string(), "Clock not initialized");
+
+        amm.timestamp
+            .store(999, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(
+            amm.quote(&quote_params)
+                .unwrap_err()
+                .downcast_ref::<SegaQuoteError>(),
+            Some(&SegaQuoteError::NotTrading)
+        );
+        amm.timestamp
+            .store(1_000, std::sync::atomic::Ordering::Relaxed);
+        assert!(amm.quote(&quote_params).is_ok());
+
+        // Disabled swaps fail the same whatever the clock
+        amm.timestamp.store(0, std::sync::atomic::Ordering::Relaxed);
+        amm.pool_state.status = 1 << 2;
+        assert_eq!(
+            amm.quote(&quote_params)
+                .unwrap_err()
+                .downcast_ref::<SegaQuoteError>(),
+            Some(&SegaQuoteError::NotTrading)
+        );
+    }
+
+    #[test]
+    fn test_effective_price() {
+        let mut amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        let account_map = test_account_map(
+            &amm,
+            transfer_fee_mint_data(9, 100, u64::MAX),
+            plain_mint_data(6),
+        );
+        amm.update(&account_map).unwrap();
+        let spot_price = amm.spot_price(&amm.pool_state.token_0_mint).unwrap();
+
+        let mut previous_price = spot_price;
+        for amount in [1_000_000, 10_000_000, 100_000_000, 1_000_000_000] {
+            let effective_price = amm.effective_price(&exact_in(&amm, amount)).unwrap();
+            assert!(effective_price < previous_price);
+            previous_price = effective_price;
+        }
+        // Small enough a swap mostly pays the 1% transfer fee and the 0.25% trade fee
+        let effective_price = amm.effective_price(&exact_in(&amm, 1_000_000)).unwrap();
+        assert!(effective_price > spot_price * Decimal::new(986, 3));
+
+        let quote_params = QuoteParams {
+            swap_mode: SwapMode::ExactOut,
+            ..exact_in(&amm, 1_000_000)
+        };
+        let quote = amm.quote(&quote_params).unwrap();
+        assert_eq!(
+            amm.effective_price(&quote_params).unwrap(),
+            Decimal::from(quote.out_amount) / Decimal::from(quote.in_amount)
+        );
+    }
+
+    #[test]
+    fn test_latest_cumulative_price() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert_eq!(amm.latest_cumulative_price(), None);
+
+        // The head sits at index 1, past the end of the array it wrapped around
+        record_price_history(&mut amm);
+        assert_eq!(amm.latest_cumulative_price(), Some((1_545 * Q32, 2_485)));
+
+        Arc::make_mut(amm.observation_state.as_mut().unwrap()).observation_index = 0;
+        assert_eq!(amm.latest_cumulative_price(), Some((1_500 * Q32, 2_470)));
+
+        Arc::make_mut(amm.observation_state.as_mut().unwrap()).initialized = false;
+        assert_eq!(amm.latest_cumulative_price(), None);
+    }
+
+    #[test]
+    fn test_update_observation_state() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert!(amm
+            .get_accounts_to_update()
+            .contains(&amm.pool_state.observation_key));
+
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        assert!(amm.observation_state.is_none());
+
+        let observation_state = ObservationState {
+            initialized: true,
+            observation_index: 0,
+            pool_id: amm.key,
+            observations: [Observation::default(); OBSERVATION_NUM],
+            padding: [0; 4],
+        };
+        account_map.insert(
+            amm.pool_state.observation_key,
+            account(observation_state_data(&observation_state), amm.program_id),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(
+            amm.observation_state.as_ref().map(|o| o.pool_id),
+            Some(amm.key)
+        );
+
+        account_map.insert(
+            amm.pool_state.observation_key,
+            account(
+                observation_state_data(&observation_state),
+                Pubkey::new_unique(),
+            ),
+        );
+        amm.update(&account_map).unwrap();
+        assert!(amm.observation_state.is_none());
+    }
+
+    #[test]
+    fn test_decode_observation() {
+        // Laid out by hand following the on chain layout, packed without alignment
+        let pool_id = Pubkey::new_unique();
+        let mut data = ObservationState::DISCRIMINATOR.to_vec();
+        data.push(1);
+        data.extend(7u16.to_le_bytes());
+        data.extend(pool_id.to_bytes());
+        for k in 0..OBSERVATION_NUM as u64 {
+            data.extend((1_000 + k).to_le_bytes());
+            data.extend((u128::from(k) * Q32).to_le_bytes());
+            data.extend((u128::from(k) * 2 * Q32).to_le_bytes());
+        }
+        data.extend([0; 32]);
+        assert_eq!(data.len(), ObservationState::LEN);
+
+        let observation_state = decode_observation(&data).unwrap();
+        let initialized = observation_state.initialized;
+        assert!(initialized);
+        assert_eq!({ observation_state.observation_index }, 7);
+        assert_eq!({ observation_state.pool_id }, pool_id);
+        let observation = { observation_state.observations }[7];
+        assert_eq!({ observation.block_timestamp }, 1_007);
+        assert_eq!({ observation.cumulative_token_0_price_x32 }, 7 * Q32);
+        assert_eq!({ observation.cumulative_token_1_price_x32 }, 14 * Q32);
+        assert_eq!(observation_state_data(&observation_state), data);
+
+        assert!(decode_observation(&data[..data.len() - 1]).is_err());
+        data[0] ^= 1;
+        assert!(decode_observation(&data).is_err());
+    }
+
+    #[test]
+    fn test_swap_account_metas_order() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let swap_params = swap_params(&amm);
+        let SwapAndAccountMetas {
+            swap,
+            account_metas,
+        } = amm.get_swap_and_account_metas(&swap_params).unwrap();
+        assert_matches!(swap, Swap::RaydiumCP);
+
+        let pool_state = &amm.pool_state;
+        let expected_account_metas = [
+            (amm.program_id, true),
+            (swap_params.token_transfer_authority, true),
+            (amm.get_authority(), false),
+            (pool_state.amm_config, false),
+            (amm.key, true),
+            (swap_params.source_token_account, true),
+            (swap_params.destination_token_account, true),
+            (pool_state.token_0_vault, true),
+            (pool_state.token_1_vault, true),
+            (pool_state.token_0_program, false),
+            (pool_state.token_1_program, false),
+            (pool_state.token_0_mint, false),
+            (pool_state.token_1_mint, false),
+            (pool_state.observation_key, true),
+        ];
+        assert_eq!(
+            account_metas
+                .iter()
+                .map(|account_meta| (account_meta.pubkey, account_meta.is_writable))
+                .collect::<Vec<_>>(),
+            expected_account_metas
+        );
+        assert!(account_metas
+            .iter()
+            .all(|account_meta| !account_meta.is_signer));
+    }
+
+    #[test]
+    fn test_failed_update_keeps_previous_state() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut updated_amm = amm.clone();
+        updated_amm.pool_state.protocol_fees_token_0 = 300;
+        updated_amm.vault_0 = VaultState::Amount(2_000_000_000);
+        let mut account_map =
+            test_account_map(&updated_amm, plain_mint_data(9), plain_mint_data(6));
+        account_map.remove(&amm.pool_state.token_1_mint);
+
+        assert!(amm.update(&account_map).is_err());
+        assert_eq!(amm.pool_state.protocol_fees_token_0, 0);
+        assert_eq!(amm.vault_0, VaultState::Amount(1_000_000_000));
+        assert_eq!(
+            amm.tradable_reserves().unwrap(),
+            (1_000_000_000, 1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn test_update_classic_and_token_2022_mints() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.pool_state.token_1_program = spl_token_2022::ID;
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, 100, 5_000),
+        );
+        amm.token_mints_and_token_programs = None;
+        assert!(amm.token_programs().is_err());
+        amm.update(&account_map).unwrap();
+
+        assert_eq!(
+            amm.token_programs().unwrap(),
+            (spl_token::ID, spl_token_2022::ID)
+        );
+        let token_mints = amm.token_mints_and_token_programs.as_ref().unwrap();
+        assert_eq!(token_mints.token0_mint.base.decimals, 9);
+        assert!(token_mints.token0_transfer_fee_config.is_none());
+        assert_eq!(token_mints.token1_mint.base.decimals, 6);
+        assert!(token_mints.token1_transfer_fee_config.is_some());
+
+        let SwapAndAccountMetas { account_metas, .. } =
+            amm.get_swap_and_account_metas(&swap_params(&amm)).unwrap();
+        assert_eq!(account_metas[9].pubkey, spl_token::ID);
+        assert_eq!(account_metas[10].pubkey, spl_token_2022::ID);
+        assert!(amm.quote(&exact_in(&amm, 1_000_000)).is_ok());
+
+        // Each mint has to be owned by the program the pool records for its side
+        for (token_program, mint) in [
+            (spl_token::ID, amm.pool_state.token_1_mint),
+            (spl_token_2022::ID, amm.pool_state.token_0_mint),
+        ] {
+            let mut account_map = account_map.clone();
+            account_map.get_mut(&mint).unwrap().owner = token_program;
+            amm.update_fingerprint = None;
+            assert!(amm
+                .update(&account_map)
+                .unwrap_err()
+                .to_string()
+                .contains("is owned by"));
+        }
+    }
+
+    #[test]
+    fn test_labeled_account_metas() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let swap_params = swap_params(&amm);
+        let SwapAndAccountMetas { account_metas, .. } =
+            amm.get_swap_and_account_metas(&swap_params).unwrap();
+        let labeled_account_metas = amm.labeled_account_metas(&swap_params).unwrap();
+
+        let (labels, labeled_metas): (Vec<_>, Vec<_>) = labeled_account_metas.into_iter().unzip();
+        assert_eq!(labels, SegaSwap::ACCOUNT_NAMES);
+        assert_eq!(labeled_metas, account_metas);
+        assert_eq!(labeled_metas[2].pubkey, amm.get_authority());
+        assert_eq!(labeled_metas[4].pubkey, amm.key);
+        assert_eq!(labeled_metas[7].pubkey, amm.pool_state.token_0_vault);
+
+        let transfer_hook_program = Pubkey::new_unique();
+        let token_mints = Arc::make_mut(amm.token_mints_and_token_programs.as_mut().unwrap());
+        token_mints.token1_transfer_hook_program = Some(transfer_hook_program);
+        let extra_account =
+            ExtraAccountMeta::new_with_pubkey(&Pubkey::new_unique(), false, false).unwrap();
+        token_mints.token1_transfer_hook_extra_accounts = Some(vec![extra_account]);
+        let labeled_account_metas = amm.labeled_account_metas(&swap_params).unwrap();
+        assert_eq!(labeled_account_metas.len(), SegaSwap::ACCOUNTS_LEN + 3);
+        assert_eq!(
+            labeled_account_metas[SegaSwap::ACCOUNTS_LEN],
+            (
+                "transfer_hook_program".to_string(),
+                AccountMeta::new_readonly(transfer_hook_program, false)
+            )
+        );
+        assert_eq!(
+            labeled_account_metas[SegaSwap::ACCOUNTS_LEN + 1].0,
+            "extra_account_metas"
+        );
+        assert_eq!(
+            labeled_account_metas[SegaSwap::ACCOUNTS_LEN + 2].0,
+            "transfer_hook_extra_account"
+        );
+    }
+
+    #[test]
+    fn test_swap_instruction() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let swap_params = swap_params(&amm);
+        let instruction = amm.swap_instruction(&swap_params, 990_000).unwrap();
+
+        assert_eq!(instruction.program_id, amm.program_id);
+        assert_eq!(instruction.accounts.len(), SegaSwap::ACCOUNTS_LEN - 1);
+        assert_eq!(
+            instruction.accounts[0],
+            AccountMeta::new(swap_params.token_transfer_authority, true)
+        );
+        assert!(instruction.accounts[1..]
+            .iter()
+            .all(|account_meta| !account_meta.is_signer));
+        assert_eq!(instruction.data.len(), 24);
+        assert_eq!(
+            instruction.data[..8],
+            solana_sdk::hash::hash(b"global:swap_base_input").to_bytes()[..8]
+        );
+        assert_eq!(instruction.data[8..16], swap_params.in_amount.to_le_bytes());
+        assert_eq!(instruction.data[16..], 990_000u64.to_le_bytes());
+
+        let exact_out_swap_params = SwapParams {
+            swap_mode: SwapMode::ExactOut,
+            ..swap_params
+        };
+        assert!(amm
+            .swap_instruction(&exact_out_swap_params, 990_000)
+            .is_err());
+    }
+
+    #[test]
+    fn test_swap_and_account_metas_unknown_source_mint() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+
+        let unknown_mint_swap_params = SwapParams {
+            source_mint: Pubkey::new_unique(),
+            ..swap_params(&amm)
+        };
+        assert!(amm
+            .get_swap_and_account_metas(&unknown_mint_swap_params)
+            .is_err());
+
+        let reverse_swap_params = SwapParams {
+            source_mint: amm.pool_state.token_1_mint,
+            destination_mint: amm.pool_state.token_0_mint,
+            ..swap_params(&amm)
+        };
+        let SwapAndAccountMetas { account_metas, .. } = amm
+            .get_swap_and_account_metas(&reverse_swap_params)
+            .unwrap();
+        assert_eq!(account_metas[7].pubkey, amm.pool_state.token_1_vault);
+        assert_eq!(account_metas[8].pubkey, amm.pool_state.token_0_vault);
+    }
+
+    #[test]
+    fn test_transfer_hook_account_metas() {
+        let hook_program_id = Pubkey::new_unique();
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let extra_account_metas =
+            get_extra_account_metas_address(&amm.pool_state.token_0_mint, &hook_program_id);
+        let mut account_map = test_account_map(
+            &amm,
+            transfer_hook_mint_data(9, hook_program_id),
+            plain_mint_data(6),
+        );
+
+        // Nothing tells yet what the hook needs
+        amm.update(&account_map).unwrap();
+        assert!(amm.get_accounts_to_update().contains(&extra_account_metas));
+        assert_eq!(
+            amm.get_swap_and_account_metas(&swap_params(&amm))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Transfer hook validation account of mint {} missing",
+                amm.pool_state.token_0_mint
+            )
+        );
+
+        account_map.insert(
+            extra_account_metas,
+            account(extra_account_metas_data(&[]).unwrap(), hook_program_id),
+        );
+        amm.update(&account_map).unwrap();
+        let SwapAndAccountMetas { account_metas, .. } =
+            amm.get_swap_and_account_metas(&swap_params(&amm)).unwrap();
+        assert_eq!(account_metas.len(), 16);
+        assert_eq!(account_metas[14].pubkey, hook_program_id);
+        assert_eq!(account_metas[15].pubkey, extra_account_metas);
+        assert_eq!(amm.get_accounts_len(), account_metas.len());
+
+        // A fixed account, then one derived from the mint, an account of the execute instruction
+        let fixed_account = Pubkey::new_unique();
+        let mint_seeds = [
+            Seed::Literal {
+                bytes: b"counter".to_vec(),
+            },
+            Seed::AccountKey { index: 1 },
+        ];
+        let mut extra_accounts = vec![
+            ExtraAccountMeta::new_with_pubkey(&fixed_account, false, false).unwrap(),
+            ExtraAccountMeta::new_with_seeds(&mint_seeds, false, true).unwrap(),
+        ];
+        account_map.insert(
+            extra_account_metas,
+            account(
+                extra_account_metas_data(&extra_accounts).unwrap(),
+                hook_program_id,
+            ),
+        );
+        amm.update(&account_map).unwrap();
+        let SwapAndAccountMetas { account_metas, .. } =
+            amm.get_swap_and_account_metas(&swap_params(&amm)).unwrap();
+        let (counter, _) = Pubkey::find_program_address(
+            &[b"counter", amm.pool_state.token_0_mint.as_ref()],
+            &hook_program_id,
+        );
+        assert_eq!(
+            account_metas[14..],
+            [
+                AccountMeta::new_readonly(hook_program_id, false),
+                AccountMeta::new_readonly(extra_account_metas, false),
+                AccountMeta::new_readonly(fixed_account, false),
+                AccountMeta::new(counter, false),
+            ]
+        );
+        assert_eq!(amm.get_accounts_len(), account_metas.len());
+
+        // The source token account is not fetched, so nothing derived from its data resolves
+        extra_accounts.push(
+            ExtraAccountMeta::new_with_seeds(
+                &[Seed::AccountData {
+                    account_index: 0,
+                    data_index: 32,
+                    length: 32,
+                }],
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        account_map.insert(
+            extra_account_metas,
+            account(
+                extra_account_metas_data(&extra_accounts).unwrap(),
+                hook_program_id,
+            ),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(
+            amm.get_swap_and_account_metas(&swap_params(&amm))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Cannot resolve extra account 2 of the transfer hook of mint {}",
+                amm.pool_state.token_0_mint
+            )
+        );
+
+        account_map.insert(extra_account_metas, account(vec![0; 16], hook_program_id));
+        assert!(amm.update(&account_map).is_err());
+    }
+
+    #[test]
+    fn test_get_accounts_len() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert_eq!(amm.get_accounts_len(), 14);
+
+        let account_map = test_account_map(
+            &amm,
+            transfer_hook_mint_data(9, Pubkey::new_unique()),
+            transfer_hook_mint_data(6, Pubkey::new_unique()),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.get_accounts_len(), 18);
+    }
+
+    #[test]
+    fn test_quote_mints_must_match_pool() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        assert!(amm
+            .quote(&QuoteParams {
+                input_mint: amm.pool_state.token_1_mint,
+                output_mint: amm.pool_state.token_0_mint,
+                ..exact_in(&amm, 1_000_000)
+            })
+            .is_ok());
+
+        let unrelated_mint = Pubkey::new_unique();
+        for (input_mint, output_mint) in [
+            (unrelated_mint, quote_params.output_mint),
+            (quote_params.input_mint, unrelated_mint),
+            (quote_params.input_mint, quote_params.input_mint),
+        ] {
+            let err = amm
+                .quote(&QuoteParams {
+                    input_mint,
+                    output_mint,
+                    ..exact_in(&amm, 1_000_000)
+                })
+                .unwrap_err();
+            assert!(err.to_string().contains("do not match pool"));
+        }
+    }
+
+    #[test]
+    fn test_quote_resilient() {
+        let mut amm = test_amm(1_000_000_000, 4_000_000_000, 2500);
+        amm.pool_state.protocol_fees_token_0 = 300;
+        amm.pool_state.fund_fees_token_1 = 100;
+        amm.vault_0 = VaultState::Amount(1_000_000_300);
+        amm.vault_1 = VaultState::Amount(4_000_000_100);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let expected_out_amount = amm.quote(&quote_params).unwrap().out_amount;
+
+        let mut missing_vault_amm = amm.clone();
+        missing_vault_amm.vault_1 = VaultState::Missing;
+        missing_vault_amm.pool_state.lp_supply = 0;
+        assert!(missing_vault_amm.quote_resilient(&quote_params).is_err());
+
+        // A pool yet to earn trade fees has the product of its net reserves at the LP supply squared
+        amm.pool_state.lp_supply = 2_000_000_000;
+        for (vault_0, vault_1) in [
+            (amm.vault_0, VaultState::Missing),
+            (VaultState::Missing, amm.vault_1),
+        ] {
+            let mut missing_vault_amm = amm.clone();
+            missing_vault_amm.vault_0 = vault_0;
+            missing_vault_amm.vault_1 = vault_1;
+            assert!(missing_vault_amm.quote(&quote_params).is_err());
+            assert_eq!(
+                missing_vault_amm
+                    .quote_resilient(&quote_params)
+                    .unwrap()
+                    .out_amount,
+                expected_out_amount
+            );
+        }
+
+        // Trade fees grew the reserves past the bound, so a missing output reserve quotes less
+        let mut missing_vault_amm = amm.clone();
+        missing_vault_amm.pool_state.lp_supply = 1_900_000_000;
+        missing_vault_amm.vault_1 = VaultState::Missing;
+        assert!(
+            missing_vault_amm
+                .quote_resilient(&quote_params)
+                .unwrap()
+                .out_amount
+                < expected_out_amount
+        );
+
+        let mut frozen_vault_amm = amm.clone();
+        frozen_vault_amm.vault_1 = VaultState::Frozen;
+        assert!(frozen_vault_amm.quote_resilient(&quote_params).is_err());
+    }
+
+    #[test]
+    fn test_quote_ladder() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let amounts = [1_000_000, 0, 10_000_000];
+
+        let quotes = amm.quote_ladder(amm.pool_state.token_0_mint, &amounts, SwapMode::ExactIn);
+        assert_eq!(quotes.len(), amounts.len());
+        for (amount, quote) in amounts.into_iter().zip(quotes) {
+            match amm.quote(&exact_in(&amm, amount)) {
+                Ok(expected_quote) => {
+                    assert_eq!(quote.unwrap().out_amount, expected_quote.out_amount)
+                }
+                Err(_) => assert!(quote.is_err()),
+            }
+        }
+
+        let quotes = amm.quote_ladder(Pubkey::new_unique(), &amounts, SwapMode::ExactIn);
+        assert!(quotes.iter().all(Result::is_err));
+    }
+
+    #[test]
+    fn test_max_input_for_impact() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let price_impact_pct = |amount| {
+            amm.quote_detailed(&exact_in(&amm, amount))
+                .unwrap()
+                .price_impact_pct
+        };
+
+        let max_input = amm
+            .max_input_for_impact(amm.pool_state.token_0_mint, 100)
+            .unwrap();
+        assert!(max_input > 7_000_000 && max_input < 8_000_000);
+        assert!(price_impact_pct(max_input) <= Decimal::new(100, 4));
+        // Rounding the output makes the impact jitter by a unit, a thousand more is past it
+        assert!(price_impact_pct(max_input + 1_000) > Decimal::new(100, 4));
+
+        // The trade fee alone is over the threshold
+        assert_eq!(
+            amm.max_input_for_impact(amm.pool_state.token_1_mint, 10)
+                .unwrap(),
+            0
+        );
+        assert!(amm.max_input_for_impact(Pubkey::new_unique(), 100).is_err());
+    }
+
+    #[test]
+    fn test_weighted_mid_price() {
+        let deep_amm = test_amm(3_000_000_000, 6_000_000_000, 2500);
+        let mut shallow_amm = test_amm(1_000_000_000, 3_000_000_000, 2500);
+        shallow_amm.pool_state.token_0_mint = deep_amm.pool_state.token_0_mint;
+        shallow_amm.pool_state.token_1_mint = deep_amm.pool_state.token_1_mint;
+        let base_mint = deep_amm.pool_state.token_0_mint;
+
+        // (2 * 3 + 3 * 1) / 4
+        assert_eq!(
+            weighted_mid_price(&[&deep_amm, &shallow_amm], &base_mint).unwrap(),
+            Decimal::new(225, 2)
+        );
+
+        let mut closed_amm = amm_with_status(1 << 2);
+        closed_amm.pool_state.token_0_mint = base_mint;
+        assert_eq!(
+            weighted_mid_price(&[&deep_amm, &closed_amm], &base_mint).unwrap(),
+            Decimal::TWO
+        );
+        assert!(weighted_mid_price(&[&closed_amm], &base_mint).is_err());
+        assert!(weighted_mid_price(&[], &base_mint).is_err());
+        assert!(weighted_mid_price(&[&deep_amm], &Pubkey::new_unique()).is_err());
+    }
+
+    #[test]
+    fn test_arbitrage_opportunity() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let same_pair_amm = |vault_0_amount, vault_1_amount| {
+            let mut other_amm = test_amm(vault_0_amount, vault_1_amount, 2500);
+            other_amm.pool_state.token_0_mint = amm.pool_state.token_0_mint;
+            other_amm.pool_state.token_1_mint = amm.pool_state.token_1_mint;
+            other_amm
+        };
+
+        // Token 1 is cheaper in the other pool, so it is bought there and sold back here
+        let other_amm = same_pair_amm(1_000_000_000, 1_100_000_000);
+        let arb_opportunity = amm.arbitrage_opportunity(&other_amm).unwrap().unwrap();
+        assert_eq!(arb_opportunity.buy_pool, other_amm.key);
+        assert_eq!(arb_opportunity.sell_pool, amm.key);
+        assert_eq!(arb_opportunity.input_mint, amm.pool_state.token_0_mint);
+        assert!(arb_opportunity.profit() > 0);
+
+        let round_trip_profit = |in_amount: u64| {
+            let intermediate_quote = other_amm
+                .quote(&QuoteParams {
+                    swap_mode: SwapMode::ExactIn,
+                    amount: in_amount,
+                    input_mint: amm.pool_state.token_0_mint,
+                    output_mint: amm.pool_state.token_1_mint,
+                })
+                .unwrap();
+            let quote = amm
+                .quote(&QuoteParams {
+                    swap_mode: SwapMode::ExactIn,
+                    amount: intermediate_quote.out_amount,
+                    input_mint: amm.pool_state.token_1_mint,
+                    output_mint: amm.pool_state.token_0_mint,
+                })
+                .unwrap();
+            quote.out_amount.saturating_sub(in_amount)
+        };
+        assert_eq!(
+            round_trip_profit(arb_opportunity.in_amount),
+            arb_opportunity.profit()
+        );
+        assert!(round_trip_profit(arb_opportunity.in_amount * 9 / 10) < arb_opportunity.profit());
+        assert!(round_trip_profit(arb_opportunity.in_amount * 11 / 10) < arb_opportunity.profit());
+
+        // The direction only depends on the prices
+        let reversed_opportunity = other_amm.arbitrage_opportunity(&amm).unwrap().unwrap();
+        assert_eq!(reversed_opportunity, arb_opportunity);
+
+        // A gap within the 0.25% fee of each pool is no opportunity
+        let other_amm = same_pair_amm(1_000_000_000, 1_004_000_000);
+        assert_eq!(amm.arbitrage_opportunity(&other_amm).unwrap(), None);
+        assert_eq!(amm.arbitrage_opportunity(&amm.clone()).unwrap(), None);
+
+        let unrelated_amm = test_amm(1_000_000_000, 1_100_000_000, 2500);
+        assert!(amm.arbitrage_opportunity(&unrelated_amm).is_err());
+
+        let losing_round_trip = ArbOpportunity {
+            out_amount: arb_opportunity.in_amount - 1,
+            ..arb_opportunity
+        };
+        assert_eq!(losing_round_trip.profit(), 0);
+    }
+
+    #[test]
+    fn test_implied_reverse_rate() {
+        let amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+        let reverse_quote = amm
+            .quote(&QuoteParams {
+                amount: quote.out_amount,
+                input_mint: quote_params.output_mint,
+                output_mint: quote_params.input_mint,
+                swap_mode: SwapMode::ExactIn,
+            })
+            .unwrap();
+
+        let reverse_rate = amm.implied_reverse_rate(&quote_params).unwrap();
+        assert_eq!(
+            reverse_rate,
+            Decimal::from(reverse_quote.out_amount) / Decimal::from(quote.out_amount)
+        );
+
+        // The fee is paid on both legs and the price moves against each, so more than 0.5% is lost
+        let forward_rate = Decimal::from(quote.out_amount) / Decimal::from(quote.in_amount);
+        let round_trip_rate = forward_rate * reverse_rate;
+        assert!(round_trip_rate < Decimal::new(995, 3));
+        assert!(round_trip_rate > Decimal::new(99, 2));
+    }
+
+    #[test]
+    fn test_quote_at() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.pool_state.open_time = 100;
+        let quote_params = exact_in(&amm, 1_000_000);
+        assert!(amm.quote(&quote_params).is_err());
+        assert!(amm.quote_at(&quote_params, 0, 99).is_err());
+
+        let quote = amm.quote_at(&quote_params, 0, 100).unwrap();
+        amm.timestamp
+            .store(100, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(
+            quote.out_amount,
+            amm.quote(&quote_params).unwrap().out_amount
+        );
+
+        // The transfer fee schedule follows the supplied epoch
+        let mut mint_1_data = transfer_fee_mint_data(6, 100, u64::MAX);
+        {
+            let mut mint = StateWithExtensionsMut::<Mint>::unpack(&mut mint_1_data).unwrap();
+            let transfer_fee_config = mint.get_extension_mut::<TransferFeeConfig>().unwrap();
+            let newer_transfer_fee = &mut transfer_fee_config.newer_transfer_fee;
+            newer_transfer_fee.epoch = 10.into();
+            newer_transfer_fee.transfer_fee_basis_points = 0.into();
+        }
+        let account_map = test_account_map(&amm, plain_mint_data(9), mint_1_data);
+        amm.update(&account_map).unwrap();
+
+        let old_epoch_quote = amm.quote_at(&quote_params, 9, 100).unwrap();
+        let new_epoch_quote = amm.quote_at(&quote_params, 10, 100).unwrap();
+        assert_eq!(new_epoch_quote.out_amount, quote.out_amount);
+        assert!(old_epoch_quote.out_amount < new_epoch_quote.out_amount);
+    }
+
+    #[test]
+    fn test_quote_follows_clock_epoch() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut mint_1_data = transfer_fee_mint_data(6, 100, u64::MAX);
+        {
+            let mut mint = StateWithExtensionsMut::<Mint>::unpack(&mut mint_1_data).unwrap();
+            let transfer_fee_config = mint.get_extension_mut::<TransferFeeConfig>().unwrap();
+            let newer_transfer_fee = &mut transfer_fee_config.newer_transfer_fee;
+            newer_transfer_fee.epoch = 10.into();
+            newer_transfer_fee.transfer_fee_basis_points = 200.into();
+        }
+        let account_map = test_account_map(&amm, plain_mint_data(9), mint_1_data);
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+        let keyed_account = KeyedAccount {
+            key: amm.key,
+            account: account_map[&amm.key].clone(),
+            params: None,
+        };
+        let mut amm = SegaAmm::from_keyed_account(&keyed_account, &amm_context).unwrap();
+        amm.update(&account_map).unwrap();
+        let quote_params = exact_in(&amm, 10_000_000);
+
+        amm_context
+            .clock_ref
+            .epoch
+            .store(9, std::sync::atomic::Ordering::Relaxed);
+        let old_epoch_quote = amm.quote(&quote_params).unwrap();
+        let gross_out_amount = amm.quote_detailed(&quote_params).unwrap().gross_out_amount;
+        assert_eq!(
+            old_epoch_quote.out_amount,
+            gross_out_amount - gross_out_amount.div_ceil(100)
+        );
+
+        // The new fee applies from its epoch on, without waiting for the next update
+        amm_context
+            .clock_ref
+            .epoch
+            .store(10, std::sync::atomic::Ordering::Relaxed);
+        let new_epoch_quote = amm.quote(&quote_params).unwrap();
+        assert_eq!(
+            new_epoch_quote.out_amount,
+            gross_out_amount - gross_out_amount.div_ceil(50)
+        );
+    }
+
+    #[test]
+    fn test_quote_uses_cached_transfer_fee_config() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+
+        // Swapping the decoded mint behind the cache shows quoting does not look at it
+        let token_mints = Arc::make_mut(amm.token_mints_and_token_programs.as_mut().unwrap());
+        assert!(token_mints.token0_transfer_fee_config.is_none());
+        assert!(token_mints.token1_transfer_fee_config.is_none());
+        token_mints.token1_mint =
+            StateWithExtensionsOwned::<Mint>::unpack(transfer_fee_mint_data(6, 100, u64::MAX))
+                .unwrap();
+        assert_eq!(
+            amm.quote(&quote_params).unwrap().out_amount,
+            quote.out_amount
+        );
+    }
+
+    #[test]
+    fn test_quote_net_input() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mint_0_data = transfer_fee_mint_data(9, 100, u64::MAX);
+        let transfer_fee_config = *StateWithExtensions::<Mint>::unpack(&mint_0_data)
+            .unwrap()
+            .get_extension::<TransferFeeConfig>()
+            .unwrap();
+        let account_map = test_account_map(&amm, mint_0_data, plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+
+        // 1% of the gross 1_000_000 goes to the transfer fee
+        let quote = amm.quote(&exact_in(&amm, 1_000_000)).unwrap();
+        let net_input_quote = amm.quote_net_input(&exact_in(&amm, 990_000)).unwrap();
+        assert_eq!(quote.in_amount, 990_000);
+        assert_eq!(net_input_quote.in_amount, 990_000);
+        assert_eq!(net_input_quote.out_amount, quote.out_amount);
+
+        let exact_out_quote_params = QuoteParams {
+            swap_mode: SwapMode::ExactOut,
+            ..exact_in(&amm, 1_000_000)
+        };
+        let quote = amm.quote(&exact_out_quote_params).unwrap();
+        let net_input_quote = amm.quote_net_input(&exact_out_quote_params).unwrap();
+        assert_eq!(net_input_quote.out_amount, quote.out_amount);
+        assert_eq!(
+            net_input_quote.in_amount
+                + transfer_fee_config
+                    .calculate_inverse_epoch_fee(0, net_input_quote.in_amount)
+                    .unwrap(),
+            quote.in_amount
+        );
+    }
+
+    // Timing, so only run on demand with `cargo test --release -- --ignored bench_quote_plain_mints`
+    #[test]
+    #[ignore]
+    fn bench_quote_plain_mints() {
+        use spl_token_2022::extension::{
+            group_member_pointer::GroupMemberPointer, group_pointer::GroupPointer,
+            metadata_pointer::MetadataPointer, mint_close_authority::MintCloseAuthority,
+        };
+        const QUOTES: u32 = 200_000;
+
+        let quote_duration = |mint_1_data| {
+            let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+            let account_map = test_account_map(&amm, plain_mint_data(9), mint_1_data);
+            amm.update(&account_map).unwrap();
+            let quote_params = exact_in(&amm, 1_000_000);
+            let start = std::time::Instant::now();
+            for _ in 0..QUOTES {
+                std::hint::black_box(amm.quote(std::hint::black_box(&quote_params)).unwrap());
+            }
+            start.elapsed() / QUOTES
+        };
+
+        // Extensions the quote has no use for, which a transfer fee lookup would have to walk past
+        let extension_types = [
+            ExtensionType::MintCloseAuthority,
+            ExtensionType::MetadataPointer,
+            ExtensionType::GroupPointer,
+            ExtensionType::GroupMemberPointer,
+        ];
+        let extended_mint_data = mint_data_with_extensions(6, &extension_types, |state| {
+            state.init_extension::<MintCloseAuthority>(true).unwrap();
+            state.init_extension::<MetadataPointer>(true).unwrap();
+            state.init_extension::<GroupPointer>(true).unwrap();
+            state.init_extension::<GroupMemberPointer>(true).unwrap();
+        });
+        let plain_duration = quote_duration(plain_mint_data(6));
+        let extended_duration = quote_duration(extended_mint_data);
+        println!(
+            "Quote with plain mints {plain_duration:?}, with extended mints {extended_duration:?}"
+        );
+
+        // Quoting reads the extensions that `update` cached, so their number makes no difference
+        assert!(extended_duration < plain_duration * 3 / 2);
+    }
+
+    #[test]
+    fn test_quote_gross_out_amount() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mint_1_data = transfer_fee_mint_data(6, 100, u64::MAX);
+        let transfer_fee_config = *StateWithExtensions::<Mint>::unpack(&mint_1_data)
+            .unwrap()
+            .get_extension::<TransferFeeConfig>()
+            .unwrap();
+        let account_map = test_account_map(&amm, plain_mint_data(9), mint_1_data);
+        amm.update(&account_map).unwrap();
+
+        for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+            let sega_quote = amm
+                .quote_detailed(&QuoteParams {
+                    swap_mode,
+                    ..exact_in(&amm, 1_000_000)
+                })
+                .unwrap();
+            let transfer_fee = transfer_fee_config
+                .calculate_epoch_fee(0, sega_quote.gross_out_amount)
+                .unwrap();
+
+            assert!(transfer_fee > 0);
+            assert_eq!(
+                sega_quote.gross_out_amount - sega_quote.quote.out_amount,
+                transfer_fee
+            );
+        }
+    }
+
+    #[test]
+    fn test_quote_exact_out_exceeding_reserve() {
+        let amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let exact_out = |amount| QuoteParams {
+            swap_mode: SwapMode::ExactOut,
+            ..exact_in(&amm, amount)
+        };
+
+        assert!(amm.quote(&exact_out(999_999_999)).is_ok());
+        assert!(amm.quote(&exact_out(1_000_000_000)).is_err());
+        assert!(amm.quote(&exact_out(2_000_000_000)).is_err());
+    }
+
+    #[test]
+    fn test_quote_destination_transfer_fee_is_capped() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let uncapped_quote = amm.quote(&quote_params).unwrap();
+
+        // 50% of the output, capped at 10
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, 5_000, 10),
+        );
+        amm.update(&account_map).unwrap();
+        let quote = amm.quote(&quote_params).unwrap();
+
+        assert_eq!(quote.in_amount, uncapped_quote.in_amount);
+        assert_eq!(quote.out_amount, uncapped_quote.out_amount - 10);
+    }
+
+    #[test]
+    fn test_quote_non_transferable_mint() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let non_transferable_mint_data =
+            mint_data_with_extensions(6, &[ExtensionType::NonTransferable], |state| {
+                state.init_extension::<NonTransferable>(true).unwrap();
+            });
+        let account_map = test_account_map(&amm, plain_mint_data(9), non_transferable_mint_data);
+        amm.update(&account_map).unwrap();
+
+        let err = amm.quote(&exact_in(&amm, 1_000_000)).unwrap_err();
+        assert_eq!(err.to_string(), "Mint is non-transferable");
+    }
+
+    #[test]
+    fn test_cached_authority() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let derived_authority = |amm: &SegaAmm| {
+            Pubkey::create_program_address(
+                &[AUTH_SEED.as_bytes(), &[amm.pool_state.auth_bump]],
+                &amm.program_id,
+            )
+            .unwrap()
+        };
+        assert_eq!(amm.get_authority(), derived_authority(&amm));
+
+        let account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.authority = Pubkey::default();
+        amm.update(&account_map).unwrap();
+        // Unchanged bump, the cached value is kept
+        assert_eq!(amm.get_authority(), Pubkey::default());
+
+        let mut updated_amm = amm.clone();
+        updated_amm.pool_state.lp_supply += 1;
+        let account_map = test_account_map(&updated_amm, plain_mint_data(9), plain_mint_data(6));
+        amm.pool_state.auth_bump = amm.pool_state.auth_bump.wrapping_add(1);
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.get_authority(), derived_authority(&amm));
+    }
+
+    #[test]
+    fn test_update_skips_unchanged_accounts() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        let update_decodes = || UPDATE_DECODES.with(std::cell::Cell::get);
+        let decodes = update_decodes();
+
+        // Identical data is not decoded again, what was loaded stays as is
+        amm.vault_0 = VaultState::Missing;
+        amm.update(&account_map).unwrap();
+        assert_eq!(update_decodes(), decodes);
+        assert_eq!(amm.vault_0, VaultState::Missing);
+
+        account_map.insert(
+            amm.pool_state.token_0_vault,
+            account(
+                token_account_data(
+                    amm.pool_state.token_0_mint,
+                    2_000_000_000,
+                    AccountState::Initialized,
+                ),
+                spl_token::ID,
+            ),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(update_decodes(), decodes + 1);
+        assert_eq!(amm.vault_0, VaultState::Amount(2_000_000_000));
+
+        account_map.remove(&amm.pool_state.token_1_vault);
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.vault_1, VaultState::Missing);
+    }
+
+    #[test]
+    fn test_clone_shares_decoded_accounts() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        record_price_history(&mut amm);
+        assert!(std::mem::size_of::<SegaAmm>() < ObservationState::LEN);
+
+        let cloned_amm = amm.clone();
+        assert!(Arc::ptr_eq(
+            amm.token_mints_and_token_programs.as_ref().unwrap(),
+            cloned_amm.token_mints_and_token_programs.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            amm.observation_state.as_ref().unwrap(),
+            cloned_amm.observation_state.as_ref().unwrap()
+        ));
+
+        // An update swaps in its own mints and leaves the clone with the previous ones
+        let account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(8));
+        amm.update(&account_map).unwrap();
+        let (token_mints, cloned_token_mints) = (
+            amm.token_mints_and_token_programs.as_deref().unwrap(),
+            cloned_amm
+                .token_mints_and_token_programs
+                .as_deref()
+                .unwrap(),
+        );
+        assert_eq!(token_mints.token1_mint.base.decimals, 8);
+        assert_eq!(cloned_token_mints.token1_mint.base.decimals, 6);
+        assert!(amm.observation_state.is_none());
+        assert!(cloned_amm.observation_state.is_some());
+    }
+
+    #[test]
+    fn test_invalid_auth_bump() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let invalid_auth_bump = (0..=u8::MAX)
+            .find(|&auth_bump| derive_authority(auth_bump, &amm.program_id).is_err())
+            .unwrap();
+        let mut corrupted_amm = amm.clone();
+        corrupted_amm.pool_state.auth_bump = invalid_auth_bump;
+        let account_map = test_account_map(&corrupted_amm, plain_mint_data(9), plain_mint_data(6));
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+        let keyed_account = KeyedAccount {
+            key: amm.key,
+            account: account_map[&amm.key].clone(),
+            params: None,
+        };
+
+        let err = SegaAmm::from_keyed_account(&keyed_account, &amm_context).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid authority bump");
+
+        // A failed update keeps the previous, valid authority for the swap accounts
+        let authority = amm.get_authority();
+        let err = amm.update(&account_map).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid authority bump");
+        assert_eq!(amm.get_authority(), authority);
+    }
+
+    #[test]
+    fn test_from_parts_quotes_like_update() {
+        let amm = test_amm(3_000_000_000, 1_000_000_000, 2500);
+
+        let mut updated_amm = amm.clone();
+        updated_amm.amm_config = None;
+        updated_amm.vault_0 = VaultState::Missing;
+        updated_amm.vault_1 = VaultState::Missing;
+        updated_amm.token_mints_and_token_programs = None;
+        updated_amm
+            .update(&test_account_map(
+                &amm,
+                plain_mint_data(9),
+                plain_mint_data(6),
+            ))
+            .unwrap();
+
+        for quote_params in [
+            exact_in(&amm, 1_000_000),
+            QuoteParams {
+                swap_mode: SwapMode::ExactOut,
+                ..exact_in(&amm, 1_000_000)
+            },
+        ] {
+            let quote = amm.quote(&quote_params).unwrap();
+            let updated_quote = updated_amm.quote(&quote_params).unwrap();
+            assert_eq!(quote.in_amount, updated_quote.in_amount);
+            assert_eq!(quote.out_amount, updated_quote.out_amount);
+            assert_eq!(quote.fee_amount, updated_quote.fee_amount);
+            assert_eq!(quote.fee_pct, updated_quote.fee_pct);
+        }
+    }
+
+    #[test]
+    fn test_accounting_inconsistent() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut inconsistent_amm = amm.clone();
+        inconsistent_amm.pool_state.protocol_fees_token_1 = 600_000_000;
+        inconsistent_amm.pool_state.fund_fees_token_1 = 400_000_001;
+        let account_map =
+            test_account_map(&inconsistent_amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+
+        assert!(amm.accounting_inconsistent());
+        assert_eq!(
+            amm.quote(&exact_in(&amm, 1_000_000))
+                .unwrap_err()
+                .to_string(),
+            "Pool accounting inconsistent, accrued fees exceed the vault balance"
+        );
+
+        inconsistent_amm.pool_state.fund_fees_token_1 = 400_000_000;
+        let account_map =
+            test_account_map(&inconsistent_amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        assert!(!amm.accounting_inconsistent());
+    }
+
+    #[test]
+    fn test_amm_config_cache() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut other_amm = test_amm(2_000_000_000, 2_000_000_000, 2500);
+        other_amm.pool_state.amm_config = amm.pool_state.amm_config;
+        let amm_config_cache = AmmConfigCache::default();
+        amm.set_amm_config_cache(amm_config_cache.clone());
+        other_amm.set_amm_config_cache(amm_config_cache.clone());
+
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        account_map.extend(test_account_map(
+            &other_amm,
+            plain_mint_data(9),
+            plain_mint_data(6),
+        ));
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm_config_cache.read().unwrap().len(), 1);
+
+        // Unchanged data is not decoded again, the cached config is used as is
+        amm_config_cache
+            .write()
+            .unwrap()
+            .get_mut(&amm.pool_state.amm_config)
+            .unwrap()
+            .1
+            .trade_fee_rate = 3000;
+        other_amm.update(&account_map).unwrap();
+        assert_eq!(other_amm.fee_rates().unwrap().0, 3000);
+
+        let mut amm_config = other_amm.amm_config.clone().unwrap();
+        amm_config.trade_fee_rate = 10_000;
+        let mut amm_config_data = Vec::new();
+        amm_config.try_serialize(&mut amm_config_data).unwrap();
+        account_map.insert(
+            amm.pool_state.amm_config,
+            account(amm_config_data, amm.program_id),
+        );
+        amm.update(&account_map).unwrap();
+        other_amm.update(&account_map).unwrap();
+        assert_eq!(amm.fee_rates().unwrap().0, 10_000);
+        assert_eq!(other_amm.fee_rates().unwrap().0, 10_000);
+    }
+
+    #[test]
+    fn test_requires_memo() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.requires_memo(), (false, false));
+
+        account_map.insert(
+            amm.pool_state.token_1_vault,
+            account(
+                memo_token_account_data(amm.pool_state.token_1_mint, 1_000_000_000),
+                spl_token_2022::ID,
+            ),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.requires_memo(), (false, true));
+        assert_eq!(amm.vault_1, VaultState::Amount(1_000_000_000));
+
+        let restored_amm = SegaAmm::from_snapshot(
+            &amm.snapshot().unwrap(),
+            &AmmContext {
+                clock_ref: ClockRef::default(),
+            },
+        )
+        .unwrap();
+        assert_eq!(restored_amm.requires_memo(), (false, true));
+    }
+
+    #[test]
+    fn test_tradable_reserves() {
+        let mut amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        amm.pool_state.protocol_fees_token_0 = 300;
+        amm.pool_state.fund_fees_token_1 = 100;
+        assert_eq!(
+            amm.tradable_reserves().unwrap(),
+            (999_999_700, 1_999_999_900)
+        );
+
+        amm.pool_state.protocol_fees_token_1 = 1_000_000_000;
+        amm.pool_state.fund_fees_token_1 = 1_000_000_001;
+        assert_eq!(
+            amm.tradable_reserves().unwrap_err().to_string(),
+            "Vault amount underflow"
+        );
+
+        amm.vault_0 = VaultState::Missing;
+        assert!(amm.tradable_reserves().is_err());
+    }
+
+    #[test]
+    fn test_quote_vault_frozen_or_missing() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+
+        amm.vault_0 = VaultState::Frozen;
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.vault_0, VaultState::Frozen);
+        assert_eq!(
+            amm.quote(&quote_params).unwrap_err().to_string(),
+            "Vault 0 frozen"
+        );
+
+        account_map.remove(&amm.pool_state.token_0_vault);
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.vault_0, VaultState::Missing);
+        assert_eq!(
+            amm.quote(&quote_params).unwrap_err().to_string(),
+            "Vault 0 missing"
+        );
+    }
+
+    #[test]
+    fn test_quote_vault_wrong_mint() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        account_map.insert(
+            amm.pool_state.token_1_vault,
+            account(
+                token_account_data(
+                    Pubkey::new_unique(),
+                    1_000_000_000,
+                    AccountState::Initialized,
+                ),
+                spl_token::ID,
+            ),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.vault_1, VaultState::WrongMint);
+        let error = amm.quote(&quote_params).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SegaQuoteError>(),
+            Some(&SegaQuoteError::VaultMintMismatch { vault: 1 })
+        );
+        assert_eq!(error.to_string(), "Vault 1 does not hold the pool mint");
+    }
+
+    #[test]
+    fn test_interest_bearing_configs() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+        assert_eq!(amm.interest_bearing_configs().unwrap(), (None, None));
+
+        let mint_1_data =
+            mint_data_with_extensions(6, &[ExtensionType::InterestBearingConfig], |state| {
+                let interest_bearing_config =
+                    state.init_extension::<InterestBearingConfig>(true).unwrap();
+                interest_bearing_config.initialization_timestamp = 1_000.into();
+                interest_bearing_config.last_update_timestamp = 1_000.into();
+                interest_bearing_config.current_rate = 500.into();
+            });
+        let account_map = test_account_map(&amm, plain_mint_data(9), mint_1_data);
+        amm.update(&account_map).unwrap();
+
+        let (interest_bearing_config_0, interest_bearing_config_1) =
+            amm.interest_bearing_configs().unwrap();
+        assert!(interest_bearing_config_0.is_none());
+        assert_eq!(
+            i16::from(interest_bearing_config_1.unwrap().current_rate),
+            500
+        );
+        // The quote is in raw amounts, unaffected by the interest
+        assert_eq!(
+            amm.quote(&quote_params).unwrap().out_amount,
+            quote.out_amount
+        );
+    }
+
+    #[test]
+    fn test_ui_amount_multipliers() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+        assert_eq!(
+            amm.ui_amount_multipliers().unwrap(),
+            (Decimal::ONE, Decimal::ONE)
+        );
+        assert_eq!(
+            amm.ui_reserves().unwrap(),
+            (Decimal::ONE, Decimal::from(1_000))
+        );
+
+        // Built by hand, `ExtensionType` has no `ScaledUiAmount` to lay it out
+        let mut mint_0_data = plain_mint_data(9);
+        mint_0_data.resize(TokenAccount::LEN, 0);
+        mint_0_data.push(AccountType::Mint as u8);
+        mint_0_data.extend_from_slice(&SCALED_UI_AMOUNT_EXTENSION_TYPE.to_le_bytes());
+        mint_0_data.extend_from_slice(&56u16.to_le_bytes());
+        mint_0_data.extend_from_slice(&[0; 32]);
+        mint_0_data.extend_from_slice(&2.0f64.to_le_bytes());
+        mint_0_data.extend_from_slice(&100i64.to_le_bytes());
+        mint_0_data.extend_from_slice(&3.0f64.to_le_bytes());
+        let account_map = test_account_map(&amm, mint_0_data, plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+
+        let (total_token_0_amount, _) = amm.tradable_reserves().unwrap();
+        let (token_0_ui_amount, token_1_ui_amount) = amm.ui_reserves().unwrap();
+        assert_eq!(token_0_ui_amount, Decimal::TWO);
+        assert_eq!(
+            token_0_ui_amount,
+            Decimal::from(total_token_0_amount) / Decimal::from(1_000_000_000) * Decimal::TWO
+        );
+        assert_eq!(token_1_ui_amount, Decimal::from(1_000));
+        assert_eq!(
+            amm.ui_spot_price(&amm.pool_state.token_0_mint).unwrap(),
+            Decimal::from(500)
+        );
+
+        // The new multiplier takes over at its timestamp
+        amm.timestamp
+            .store(100, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(
+            amm.ui_amount_multipliers().unwrap(),
+            (Decimal::from(3), Decimal::ONE)
+        );
+        // The quote is in raw amounts, unaffected by the multiplier
+        assert_eq!(
+            amm.quote(&quote_params).unwrap().out_amount,
+            quote.out_amount
+        );
+    }
+
+    #[test]
+    fn test_concurrent_quotes() {
+        let amm = Arc::new(test_amm(1_000_000_000, 2_000_000_000, 2500));
+        let quote_params = exact_in(&amm, 1_000_000);
+        let expected_out_amount = amm.quote(&quote_params).unwrap().out_amount;
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let amm = Arc::clone(&amm);
+                std::thread::spawn(move || {
+                    (0..100)
+                        .map(|_| amm.quote(&quote_params).unwrap().out_amount)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle
+                .join()
+                .unwrap()
+                .iter()
+                .all(|out_amount| *out_amount == expected_out_amount));
+        }
+        // Every thread quoted the instance itself
+        assert_eq!(Arc::strong_count(&amm), 1);
+    }
+
+    #[test]
+    fn test_quote_ui() {
+        let amm = test_amm(1_000_000_000_000, 2_000_000_000, 2500);
+        let input_mint = amm.pool_state.token_0_mint;
+        let quote = amm.quote(&exact_in(&amm, 1_500_000_000)).unwrap();
+        assert_eq!(
+            amm.quote_ui(input_mint, Decimal::new(15, 1), SwapMode::ExactIn)
+                .unwrap(),
+            (
+                Decimal::new(15, 1),
+                Decimal::new(quote.out_amount as i64, 6)
+            )
+        );
+
+        // 1.9 raw units of token 0 in are 1, 1.1 raw units of token 1 out are 2
+        let (in_ui_amount, _) = amm
+            .quote_ui(input_mint, Decimal::new(19, 10), SwapMode::ExactIn)
+            .unwrap();
+        assert_eq!(in_ui_amount, Decimal::new(1, 9));
+        let (in_ui_amount, out_ui_amount) = amm
+            .quote_ui(input_mint, Decimal::new(11, 7), SwapMode::ExactOut)
+            .unwrap();
+        assert_eq!(out_ui_amount, Decimal::new(2, 6));
+        let quote = amm
+            .quote(&QuoteParams {
+                swap_mode: SwapMode::ExactOut,
+                ..exact_in(&amm, 2)
+            })
+            .unwrap();
+        assert_eq!(in_ui_amount, Decimal::new(quote.in_amount as i64, 9));
+
+        assert!(amm
+            .quote_ui(input_mint, Decimal::NEGATIVE_ONE, SwapMode::ExactIn)
+            .is_err());
+        assert!(amm
+            .quote_ui(Pubkey::new_unique(), Decimal::ONE, SwapMode::ExactIn)
+            .is_err());
+    }
+
+    #[test]
+    fn test_has_permanent_delegate() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert_eq!(amm.has_permanent_delegate(), (false, false));
+
+        let permanent_delegate_mint_data = |delegate: Option<Pubkey>| {
+            mint_data_with_extensions(6, &[ExtensionType::PermanentDelegate], |state| {
+                let permanent_delegate = state.init_extension::<PermanentDelegate>(true).unwrap();
+                permanent_delegate.delegate = delegate.try_into().unwrap();
+            })
+        };
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            permanent_delegate_mint_data(Some(Pubkey::new_unique())),
+        );
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.has_permanent_delegate(), (false, true));
+        assert!(amm.quote(&exact_in(&amm, 1_000_000)).is_ok());
+
+        // The extension without a delegate grants nothing
+        let account_map =
+            test_account_map(&amm, permanent_delegate_mint_data(None), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.has_permanent_delegate(), (false, false));
+    }
+
+    #[test]
+    fn test_quote_vault_frozen_by_default_account_state() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.vault_1 = VaultState::Frozen;
+        let mint_1_data =
+            mint_data_with_extensions(6, &[ExtensionType::DefaultAccountState], |state| {
+                let default_account_state =
+                    state.init_extension::<DefaultAccountState>(true).unwrap();
+                default_account_state.state = AccountState::Frozen as u8;
+            });
+        let account_map = test_account_map(&amm, plain_mint_data(9), mint_1_data);
+        amm.update(&account_map).unwrap();
+
+        assert_eq!(amm.vault_1, VaultState::Frozen);
+        assert_eq!(
+            amm.quote(&exact_in(&amm, 1_000_000))
+                .unwrap_err()
+                .to_string(),
+            "Vault 1 frozen, its mint freezes new token accounts by default"
+        );
+    }
+
+    #[test]
+    fn test_quote_malformed_fee_rates() {
+        // Rates past the denominator cannot come from the program, they fail instead of panicking
+        for trade_fee_rate in [FEE_RATE_DENOMINATOR_VALUE + 1, u64::MAX] {
+            let amm = test_amm(1_000_000_000, 1_000_000_000, trade_fee_rate);
+            for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+                let quote_params = QuoteParams {
+                    swap_mode,
+                    ..exact_in(&amm, 1_000_000)
+                };
+                let error = amm.quote(&quote_params).unwrap_err();
+                assert_eq!(
+                    error.downcast_ref::<SegaQuoteError>(),
+                    Some(&SegaQuoteError::CurveFailure)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_quote_error_downcast() {
+        let quote_error = |amm: &SegaAmm, amount| {
+            *amm.quote(&exact_in(amm, amount))
+                .unwrap_err()
+                .downcast_ref::<SegaQuoteError>()
+                .unwrap()
+        };
+
+        let mut amm = amm_with_status(1 << 2);
+        assert_eq!(quote_error(&amm, 1_000_000), SegaQuoteError::NotTrading);
+
+        amm.pool_state.status = 0;
+        amm.vault_1 = VaultState::Missing;
+        assert_eq!(
+            quote_error(&amm, 1_000_000),
+            SegaQuoteError::VaultMissing { vault: 1 }
+        );
+
+        amm.vault_1 = VaultState::Frozen;
+        assert_eq!(
+            quote_error(&amm, 1_000_000),
+            SegaQuoteError::VaultFrozen {
+                vault: 1,
+                by_default_account_state: false
+            }
+        );
+
+        amm.vault_1 = VaultState::Amount(1_000_000_000);
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, MAX_FEE_BASIS_POINTS, u64::MAX),
+        );
+        amm.update(&account_map).unwrap();
+        let amm_one_for_zero = |amount| {
+            *amm.quote(&QuoteParams {
+                amount,
+                input_mint: amm.pool_state.token_1_mint,
+                output_mint: amm.pool_state.token_0_mint,
+                swap_mode: SwapMode::ExactIn,
+            })
+            .unwrap_err()
+            .downcast_ref::<SegaQuoteError>()
+            .unwrap()
+        };
+        assert_eq!(amm_one_for_zero(1_000_000), SegaQuoteError::AmountTooLow);
+
+        let empty_amm = test_amm(0, 1_000_000_000, 2500);
+        assert_eq!(
+            quote_error(&empty_amm, 1_000_000),
+            SegaQuoteError::EmptyReserve
+        );
+        assert_eq!(
+            empty_amm
+                .quote(&exact_in(&empty_amm, 1_000_000))
+                .unwrap_err()
+                .to_string(),
+            "Pool has empty reserve"
+        );
+
+        // Other failures are not typed
+        assert!(amm
+            .quote(&QuoteParams {
+                amount: 1_000_000,
+                input_mint: Pubkey::new_unique(),
+                output_mint: amm.pool_state.token_0_mint,
+                swap_mode: SwapMode::ExactIn,
+            })
+            .unwrap_err()
+            .downcast_ref::<SegaQuoteError>()
+            .is_none());
+    }
+
+    #[test]
+    fn test_fee_pct_and_curve_amount_edges() {
+        assert_eq!(fee_pct(0, 0), Decimal::ZERO);
+        assert_eq!(fee_pct(25, 10_000), Decimal::new(25, 4));
+
+        assert_eq!(
+            curve_amount(u64::MAX.into(), "Amount out").unwrap(),
+            u64::MAX
+        );
+        assert_eq!(
+            curve_amount(u128::from(u64::MAX) + 1, "Amount out")
+                .unwrap_err()
+                .to_string(),
+            "Amount out 18446744073709551616 does not fit in a u64"
+        );
+
+        // Buying all but one token of the reserve costs more than a u64 of input
+        let amm = test_amm(u64::MAX, u64::MAX, 0);
+        let quote_params = QuoteParams {
+            amount: u64::MAX - 1,
+            swap_mode: SwapMode::ExactOut,
+            ..exact_in(&amm, 0)
+        };
+        let err = amm.quote(&quote_params).unwrap_err().to_string();
+        assert!(err.starts_with("Amount in "));
+        assert!(err.ends_with(" does not fit in a u64"));
+    }
+
+    #[test]
+    fn test_vault_amount_without_fee_overflow() {
+        let pool_state = PoolState {
+            protocol_fees_token_0: u64::MAX,
+            fund_fees_token_0: u64::MAX,
+            protocol_fees_token_1: u64::MAX,
+            fund_fees_token_1: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vault_amount_without_fee(&pool_state, u64::MAX, u64::MAX),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn test_exact_in_amount_within_requested() {
+        let mut amm = test_amm(1_000_000_000, 3_000_000_000, 2500);
+        let account_map = test_account_map(
+            &amm,
+            transfer_fee_mint_data(9, 150, 1_000),
+            plain_mint_data(6),
+        );
+        amm.update(&account_map).unwrap();
+        for zero_for_one in [true, false] {
+            let mut amount = 1;
+            while amount < 1_000_000_000_000 {
+                let mut quote_params = exact_in(&amm, amount);
+                if !zero_for_one {
+                    std::mem::swap(&mut quote_params.input_mint, &mut quote_params.output_mint);
+                }
+                if let Ok(quote) = amm.quote(&quote_params) {
+                    assert!(
+                        quote.in_amount <= amount,
+                        "{} > {}",
+                        quote.in_amount,
+                        amount
+                    );
+                }
+                amount = amount * 3 + 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_vaults_only() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        amm.update(&account_map).unwrap();
+        let token_mints = amm.token_mints_and_token_programs.clone().unwrap();
+        let trade_fee_rate = amm.amm_config.as_ref().unwrap().trade_fee_rate;
+
+        let mut pool_state = amm.pool_state.clone();
+        pool_state.protocol_fees_token_0 = 1_000;
+        let mut pool_state_data = Vec::new();
+        pool_state.try_serialize(&mut pool_state_data).unwrap();
+        account_map.get_mut(&amm.key).unwrap().data = pool_state_data;
+        account_map.get_mut(&pool_state.token_0_vault).unwrap().data = token_account_data(
+            pool_state.token_0_mint,
+            2_000_000_000,
+            AccountState::Initialized,
+        );
+        // Neither mints nor config are read, dropping them shows it
+        account_map.remove(&pool_state.token_0_mint);
+        account_map.remove(&pool_state.amm_config);
+        amm.update_vaults_only(&account_map).unwrap();
+
+        assert_eq!(amm.vault_0, VaultState::Amount(2_000_000_000));
+        assert_eq!(amm.pool_state.protocol_fees_token_0, 1_000);
+        assert!(Arc::ptr_eq(
+            amm.token_mints_and_token_programs.as_ref().unwrap(),
+            &token_mints
+        ));
+        assert_eq!(
+            amm.amm_config.as_ref().unwrap().trade_fee_rate,
+            trade_fee_rate
+        );
+        assert_eq!(
+            amm.tradable_reserves().unwrap(),
+            (1_999_999_000, 1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn test_update_without_amm_config() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut account_map = test_account_map(&amm, plain_mint_data(9), plain_mint_data(6));
+        account_map.remove(&amm.pool_state.amm_config);
+
+        // Nothing to fall back on yet
+        let amm_config = amm.amm_config.take();
+        assert!(amm.update(&account_map).is_err());
+        assert!(amm.amm_config.is_none());
+
+        amm.amm_config = amm_config;
+        amm.update(&account_map).unwrap();
+        assert_eq!(amm.amm_config.as_ref().unwrap().trade_fee_rate, 2500);
+        assert!(amm.quote(&exact_in(&amm, 1_000_000)).is_ok());
+
+        // A cached config only stands in for the same key
+        amm.pool_state.amm_config = Pubkey::new_unique();
+        amm.update_fingerprint = None;
+        assert!(amm.update(&account_map).is_err());
+    }
+
+    #[test]
+    fn test_exact_out_output_unreachable() {
+        let mut amm = test_amm(1_000_000, 1_000_000, 2500);
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, 1_000, u64::MAX),
+        );
+        amm.update(&account_map).unwrap();
+        let exact_out = |amount| QuoteParams {
+            amount,
+            input_mint: amm.pool_state.token_0_mint,
+            output_mint: amm.pool_state.token_1_mint,
+            swap_mode: SwapMode::ExactOut,
+        };
+
+        // Grossed up by the 10% fee, the target is beyond the reserve even below it
+        for amount in [950_000, 999_999, 1_000_000, u64::MAX] {
+            let error = amm.quote(&exact_out(amount)).unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<SegaQuoteError>(),
+                Some(&SegaQuoteError::OutputUnreachable)
+            );
+            assert_eq!(error.to_string(), "Target output unreachable");
+        }
+
+        let quote = amm.quote(&exact_out(800_000)).unwrap();
+        assert_eq!(quote.out_amount, 800_000);
+        assert!(quote.in_amount > 1_000_000);
+    }
+
+    #[test]
+    fn test_exact_out_requiring_no_input() {
+        let amm = test_amm(1_000, 1_000_000, 2500);
+        let exact_out = |amount| QuoteParams {
+            amount,
+            input_mint: amm.pool_state.token_0_mint,
+            output_mint: amm.pool_state.token_1_mint,
+            swap_mode: SwapMode::ExactOut,
+        };
+
+        // 1_000 * 1 / 999_999 rounds to nothing, the program would pay out for free
+        let error = amm.quote(&exact_out(1)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SegaQuoteError>(),
+            Some(&SegaQuoteError::AmountTooLow)
+        );
+
+        let quote = amm.quote(&exact_out(1_000)).unwrap();
+        assert_eq!(quote.out_amount, 1_000);
+        assert!(quote.in_amount > 0);
+    }
+
+    #[test]
+    fn test_status() {
+        for status in 0..8u8 {
+            let amm = amm_with_status(status);
+            assert_eq!(
+                amm.status(),
+                PoolStatus {
+                    deposit_enabled: status & 1 == 0,
+                    withdraw_enabled: status & 2 == 0,
+                    swap_enabled: status & 4 == 0,
+                }
+            );
+        }
+
+        // Only the swap bit matters to quote
+        let amm = amm_with_status(0b011);
+        assert!(amm.quote(&exact_in(&amm, 1_000_000)).is_ok());
+        let amm = amm_with_status(0b100);
+        assert!(amm.quote(&exact_in(&amm, 1_000_000)).is_err());
+    }
+
+    #[test]
+    fn test_depth_curve() {
+        let amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        let input_mint = amm.pool_state.token_0_mint;
+        assert!(amm.depth_curve(input_mint, 0, 10).unwrap().is_empty());
+        assert!(amm
+            .depth_curve(input_mint, 1_000_000, 0)
+            .unwrap()
+            .is_empty());
+        assert!(amm
+            .depth_curve(Pubkey::new_unique(), 1_000_000, 10)
+            .is_err());
+
+        let depth_curve = amm.depth_curve(input_mint, 1_000_000_000, 4).unwrap();
+        let amounts: Vec<u64> = depth_curve.iter().map(|(amount, _)| *amount).collect();
+        assert_eq!(
+            amounts,
+            [250_000_000, 500_000_000, 750_000_000, 1_000_000_000]
+        );
+        for (amount, out_amount) in &depth_curve {
+            assert_eq!(
+                *out_amount,
+                amm.quote(&exact_in(&amm, *amount)).unwrap().out_amount
+            );
+        }
+        // Each step buys less than the one before
+        let steps: Vec<u64> = [(0, 0)]
+            .iter()
+            .chain(&depth_curve)
+            .zip(&depth_curve)
+            .map(|((_, previous), (_, out_amount))| out_amount - previous)
+            .collect();
+        assert!(steps.windows(2).all(|steps| steps[1] < steps[0]));
+
+        // Fewer units than samples repeats amounts rather than failing
+        let depth_curve = amm.depth_curve(input_mint, 2, 4).unwrap();
+        assert_eq!(depth_curve.len(), 4);
+        assert_eq!(depth_curve[3].0, 2);
+    }
+
+    #[test]
+    fn test_quote_observer() {
+        #[derive(Default)]
+        struct Outcomes(RwLock<Vec<std::result::Result<(), Option<SegaQuoteError>>>>);
+
+        impl QuoteObserver for Outcomes {
+            fn on_quote_success(&self, _elapsed: std::time::Duration) {
+                self.0.write().unwrap().push(Ok(()));
+            }
+
+            fn on_quote_failure(
+                &self,
+                _elapsed: std::time::Duration,
+                error: Option<SegaQuoteError>,
+            ) {
+                self.0.write().unwrap().push(Err(error));
+            }
+        }
+
+        let outcomes = Arc::new(Outcomes::default());
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let quote_params = exact_in(&amm, 1_000_000);
+        amm.quote(&quote_params).unwrap();
+        amm.set_quote_observer(Some(outcomes.clone()));
+        amm.quote(&quote_params).unwrap();
+        let mut not_trading_amm = amm.clone();
+        not_trading_amm.pool_state.status = 1 << 2;
+        assert!(not_trading_amm.quote(&quote_params).is_err());
+        assert!(amm
+            .quote(&QuoteParams {
+                input_mint: Pubkey::new_unique(),
+                ..quote_params
+            })
+            .is_err());
+        amm.set_quote_observer(None);
+        amm.quote(&quote_params).unwrap();
+
+        assert_eq!(
+            *outcomes.0.read().unwrap(),
+            [Ok(()), Err(Some(SegaQuoteError::NotTrading)), Err(None)]
+        );
+
+        // A search reports the quote it settles on rather than each of its probes
+        amm.set_quote_observer(Some(outcomes.clone()));
+        amm.max_input_for_impact(amm.pool_state.token_0_mint, 100)
+            .unwrap();
+        assert_eq!(outcomes.0.read().unwrap()[3..], [Ok(())]);
+        assert_eq!(
+            amm.max_input_for_impact(amm.pool_state.token_1_mint, 10)
+                .unwrap(),
+            0
+        );
+        assert_eq!(outcomes.0.read().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_round_trip_loss() {
+        let shallow_amm = test_amm(10_000, 10_000, 2500);
+        let deep_amm = test_amm(10_000_000_000, 10_000_000_000, 2500);
+        for amm in [&shallow_amm, &deep_amm] {
+            assert!(amm
+                .round_trip_loss(Pubkey::new_unique(), 1_000_000)
+                .is_err());
+        }
+
+        // Both swap a tenth of the reserve, so only the rounding tells them apart
+        let shallow_loss = shallow_amm
+            .round_trip_loss(shallow_amm.pool_state.token_0_mint, 1_000)
+            .unwrap();
+        let deep_loss = deep_amm
+            .round_trip_loss(deep_amm.pool_state.token_0_mint, 1_000_000_000)
+            .unwrap();
+        // 1_000_000_000 in, 907_024_323 out and 995_458_164 back, under the two 0.25% trade fees
+        // as the second one is taken on the smaller output
+        assert_eq!(deep_loss, Decimal::new(4_541_836, 9));
+        // 1_000 in, 906 out and 993 back
+        assert_eq!(shallow_loss, Decimal::new(7, 3));
+        assert!(shallow_loss > deep_loss);
+    }
+
+    #[test]
+    fn test_pool_state() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.pool_state.lp_supply = 42;
+        let pool_state = amm.pool_state();
+        assert_eq!(pool_state.lp_supply, 42);
+        assert_eq!(
+            [pool_state.token_0_mint, pool_state.token_1_mint],
+            amm.get_reserve_mints()[..]
+        );
+    }
+
+    #[test]
+    fn test_pool_info() {
+        let mut amm = amm_with_status(1 << 1);
+        amm.pool_state.open_time = 100;
+        amm.pool_state.protocol_fees_token_0 = 300;
+        amm.pool_state.fund_fees_token_1 = 100;
+
+        let pool_info = amm.pool_info();
+        assert_eq!(pool_info.amm_config, amm.pool_state.amm_config);
+        assert_eq!(pool_info.lp_mint, amm.lp_mint());
+        assert_eq!(pool_info.open_time, 100);
+        assert_eq!(pool_info.auth_bump, amm.pool_state.auth_bump);
+        assert!(pool_info.deposit_enabled);
+        assert!(!pool_info.withdraw_enabled);
+        assert!(pool_info.swap_enabled);
+        assert_eq!(pool_info.protocol_fees_token_0, 300);
+        assert_eq!(pool_info.fund_fees_token_1, 100);
+
+        amm.pool_state.status = 1 << 2;
+        let pool_info = amm.pool_info();
+        assert!(pool_info.withdraw_enabled);
+        assert!(!pool_info.swap_enabled);
+    }
+
+    #[test]
+    fn test_is_tradable() {
+        let mut amm = amm_with_status(0);
+        assert!(amm.is_tradable());
+
+        amm.pool_state.open_time = 100;
+        assert!(!amm.is_tradable());
+        assert_eq!(
+            amm.quote(&exact_in(&amm, 1_000_000))
+                .unwrap_err()
+                .to_string(),
+            "Pool is not trading"
+        );
+        amm.timestamp
+            .store(100, std::sync::atomic::Ordering::Relaxed);
+        assert!(amm.is_tradable());
+
+        assert!(!amm_with_status(1 << 2).is_tradable());
+    }
+
+    #[test]
+    fn test_quote_before_open_time() {
+        let mut amm = amm_with_status(0);
+        amm.pool_state.open_time = 100;
+        let quote_params = exact_in(&amm, 1_000_000);
+        assert!(amm.quote(&quote_params).is_err());
+
+        amm.set_quote_before_open_time(true);
+        assert!(!amm.is_tradable());
+        assert_eq!(
+            amm.quote(&quote_params).unwrap().out_amount,
+            amm.quote_at(&quote_params, 0, 100).unwrap().out_amount
+        );
+
+        amm.pool_state.status = 1 << 2;
+        assert!(amm.quote(&quote_params).is_err());
+    }
+
+    #[test]
+    fn test_max_price_impact_bps() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let small_quote_params = exact_in(&amm, 1_000_000);
+        let large_quote_params = exact_in(&amm, 100_000_000);
+        let small_sega_quote = amm.quote_detailed(&small_quote_params).unwrap();
+        let large_sega_quote = amm.quote_detailed(&large_quote_params).unwrap();
+        assert!(small_sega_quote.price_impact_pct < Decimal::new(1, 2));
+        assert!(large_sega_quote.price_impact_pct > Decimal::new(1, 2));
+
+        amm.set_max_price_impact_bps(Some(100));
+        assert_eq!(
+            amm.quote(&small_quote_params).unwrap().out_amount,
+            small_sega_quote.quote.out_amount
+        );
+        assert_eq!(
+            *amm.quote(&large_quote_params)
+                .unwrap_err()
+                .downcast_ref::<SegaQuoteError>()
+                .unwrap(),
+            SegaQuoteError::PriceImpactTooHigh
+        );
+
+        amm.set_max_price_impact_bps(None);
+        assert!(amm.quote(&large_quote_params).is_ok());
+    }
+
+    #[test]
+    fn test_quote_partial_fill() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let (_, total_output_token_amount) = amm.tradable_reserves().unwrap();
+        let quote_params = QuoteParams {
+            swap_mode: SwapMode::ExactOut,
+            ..exact_in(&amm, 2 * total_output_token_amount)
+        };
+        assert!(amm.quote(&quote_params).is_err());
+
+        amm.set_partial_fill(true);
+        let sega_quote = amm.quote_detailed(&quote_params).unwrap();
+        assert!(sega_quote.partial_fill);
+        assert_eq!(sega_quote.quote.out_amount, total_output_token_amount - 1);
+        assert_eq!(
+            sega_quote.quote.in_amount,
+            amm.quote(&QuoteParams {
+                amount: total_output_token_amount - 1,
+                ..quote_params
+            })
+            .unwrap()
+            .in_amount
+        );
+
+        // Amounts the pool can fill are quoted as usual
+        let sega_quote = amm.quote_detailed(&exact_in(&amm, 1_000_000)).unwrap();
+        assert!(!sega_quote.partial_fill);
+        assert_eq!(sega_quote.quote.in_amount, 1_000_000);
+
+        // Failures a smaller amount would only hide surface directly
+        amm.set_max_price_impact_bps(Some(100));
+        assert_eq!(
+            amm.quote(&exact_in(&amm, 100_000_000))
+                .unwrap_err()
+                .downcast_ref::<SegaQuoteError>(),
+            Some(&SegaQuoteError::PriceImpactTooHigh)
+        );
+        amm.set_max_price_impact_bps(None);
+        let unknown_mint_quote_params = QuoteParams {
+            input_mint: Pubkey::new_unique(),
+            ..quote_params
+        };
+        assert!(amm.quote(&unknown_mint_quote_params).is_err());
+
+        // An input overflowing a u64 is scaled down to one that fits
+        let mut overflowing_amm = test_amm(u64::MAX, u64::MAX, 0);
+        overflowing_amm.set_partial_fill(true);
+        let sega_quote = overflowing_amm
+            .quote_detailed(&QuoteParams {
+                amount: u64::MAX - 1,
+                swap_mode: SwapMode::ExactOut,
+                ..exact_in(&overflowing_amm, 0)
+            })
+            .unwrap();
+        assert!(sega_quote.partial_fill);
+        assert!(sega_quote.quote.out_amount < u64::MAX - 1);
+
+        amm.pool_state.status = 1 << 2;
+        assert_eq!(
+            amm.quote(&quote_params).unwrap_err().to_string(),
+            "Pool is not trading"
+        );
+    }
+
+    #[test]
+    fn test_fee_rates() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let amm_config = amm.amm_config.as_mut().unwrap();
+        amm_config.protocol_fee_rate = 120_000;
+        amm_config.fund_fee_rate = 40_000;
+        assert_eq!(amm.fee_rates().unwrap(), (2500, 120_000, 40_000));
+
+        amm.amm_config = None;
+        assert!(amm.fee_rates().is_err());
+    }
+
+    #[test]
+    fn test_has_dynamic_accounts() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert!(!amm.has_dynamic_accounts());
+
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_hook_mint_data(6, Pubkey::new_unique()),
+        );
+        amm.update(&account_map).unwrap();
+        assert!(amm.has_dynamic_accounts());
+    }
+
+    #[test]
+    fn test_uses_wrapped_sol() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        assert!(!amm.uses_wrapped_sol());
+        assert!(amm.get_user_setup().is_none());
+
+        amm.pool_state.token_1_mint = spl_token::native_mint::ID;
+        assert!(amm.uses_wrapped_sol());
+    }
+
+    #[test]
+    fn test_min_amount_out() {
+        let quote = Quote {
+            out_amount: 1_000_000,
+            ..Default::default()
+        };
+
+        assert_eq!(min_amount_out(&quote, 0), 1_000_000);
+        assert_eq!(min_amount_out(&quote, 50), 995_000);
+        assert_eq!(min_amount_out(&quote, 10_000), 0);
+        assert_eq!(min_amount_out(&quote, u16::MAX), 0);
+
+        let quote = Quote {
+            out_amount: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(min_amount_out(&quote, 0), u64::MAX);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut amm = test_amm(3_000_000_000, 1_000_000_000, 2500);
+        let account_map = test_account_map(
+            &amm,
+            plain_mint_data(9),
+            transfer_fee_mint_data(6, 100, 5_000),
+        );
+        amm.update(&account_map).unwrap();
+
+        let snapshot = serde_json::to_string(&amm.snapshot().unwrap()).unwrap();
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+        let restored_amm =
+            SegaAmm::from_snapshot(&serde_json::from_str(&snapshot).unwrap(), &amm_context)
+                .unwrap();
+
+        let (token_mints, restored_token_mints) = (
+            amm.token_mints_and_token_programs.as_ref().unwrap(),
+            restored_amm
+                .token_mints_and_token_programs
+                .as_deref()
+                .unwrap(),
+        );
+        assert_eq!(token_mints.token1_mint, restored_token_mints.token1_mint);
+        assert_eq!(restored_amm.get_authority(), amm.get_authority());
+
+        let quote_params = exact_in(&amm, 1_000_000);
+        let quote = amm.quote(&quote_params).unwrap();
+        let restored_quote = restored_amm.quote(&quote_params).unwrap();
+        assert_eq!(restored_quote.in_amount, quote.in_amount);
+        assert_eq!(restored_quote.out_amount, quote.out_amount);
+
+        // The restored pool follows the clock it was bound to
+        amm_context
+            .clock_ref
+            .unix_timestamp
+            .store(42, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(
+            restored_amm
+                .timestamp
+                .load(std::sync::atomic::Ordering::Relaxed),
+            42
+        );
+    }
+
+    #[test]
+    fn test_snapshot_round_trip_transfer_hook() {
+        let hook_program_id = Pubkey::new_unique();
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        let mut account_map = test_account_map(
+            &amm,
+            transfer_hook_mint_data(9, hook_program_id),
+            plain_mint_data(6),
+        );
+        let extra_account =
+            ExtraAccountMeta::new_with_pubkey(&Pubkey::new_unique(), false, true).unwrap();
+        account_map.insert(
+            get_extra_account_metas_address(&amm.pool_state.token_0_mint, &hook_program_id),
+            account(
+                extra_account_metas_data(&[extra_account]).unwrap(),
+                hook_program_id,
+            ),
+        );
+        amm.update(&account_map).unwrap();
+
+        let snapshot = serde_json::to_string(&amm.snapshot().unwrap()).unwrap();
+        let amm_context = AmmContext {
+            clock_ref: ClockRef::default(),
+        };
+        let restored_amm =
+            SegaAmm::from_snapshot(&serde_json::from_str(&snapshot).unwrap(), &amm_context)
+                .unwrap();
+
+        let swap_params = swap_params(&amm);
+        let SwapAndAccountMetas { account_metas, .. } =
+            amm.get_swap_and_account_metas(&swap_params).unwrap();
+        let SwapAndAccountMetas {
+            account_metas: restored_account_metas,
+            ..
+        } = restored_amm
+            .get_swap_and_account_metas(&swap_params)
+            .unwrap();
+        assert_eq!(account_metas.len(), SegaSwap::ACCOUNTS_LEN + 3);
+        assert_eq!(restored_account_metas, account_metas);
+        assert_eq!(restored_amm.get_accounts_len(), amm.get_accounts_len());
+    }
+
+    #[test]
+    fn test_quote_deposit() {
+        let mut amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        amm.pool_state.lp_supply = 1_000_000_000;
+
+        assert_eq!(
+            amm.quote_deposit(1_000, 5_000).unwrap(),
+            DepositQuote {
+                lp_amount: 1_000,
+                amount_0: 1_000,
+                amount_1: 2_000,
+            }
+        );
+        assert!(amm.quote_deposit(0, 5_000).is_err());
+
+        // bit0 disables deposit
+        amm.pool_state.status = 1;
+        assert!(amm.quote_deposit(1_000, 5_000).is_err());
+    }
+
+    #[test]
+    fn test_with_simulated_deposit() {
+        let mut amm = test_amm(1_000_000_000, 2_000_000_000, 2500);
+        amm.pool_state.lp_supply = 1_000_000_000;
+        let quote_params = exact_in(&amm, 10_000_000);
+
+        let deposited_amm = amm
+            .with_simulated_deposit(1_000_000_000, 5_000_000_000)
+            .unwrap();
+        assert_eq!(amm.vault_0, VaultState::Amount(1_000_000_000));
+        assert_eq!(deposited_amm.vault_0, VaultState::Amount(2_000_000_000));
+        assert_eq!(deposited_amm.vault_1, VaultState::Amount(4_000_000_000));
+        assert_eq!(deposited_amm.lp_supply(), 2_000_000_000);
+
+        let quote = amm.quote(&quote_params).unwrap();
+        let deposited_quote = deposited_amm.quote(&quote_params).unwrap();
+        assert!(deposited_quote.out_amount > quote.out_amount);
+        let token_0_mint = amm.pool_state.token_0_mint;
+        assert_eq!(
+            deposited_amm.spot_price(&token_0_mint).unwrap(),
+            amm.spot_price(&token_0_mint).unwrap()
+        );
+
+        amm.vault_1 = VaultState::Missing;
+        assert!(amm.with_simulated_deposit(1_000, 5_000).is_err());
+    }
+
+    #[test]
+    fn test_lp_mint_and_supply() {
+        let mut amm = test_amm(1_000_000_000, 1_000_000_000, 2500);
+        amm.pool_state.lp_mint = Pubkey::new_unique();
+        amm.pool_state.lp_supply = 1_000_000_000;
+
+        assert_eq!(amm.lp_mint(), amm.pool_state.lp_mint);
+        assert_eq!(amm.lp_supply(), 1_000_000_000);
+    }
+
+    #[test]
+    fn test_quote_withdraw() {
+        let mut amm = test_amm(1_000_000_300, 3_000_000_000, 2500);
+        amm.pool_state.lp_supply = 1_000_000_000;
+        amm.pool_state.protocol_fees_token_0 = 300;
+
+        assert_eq!(amm.quote_withdraw(1_000).unwrap(), (1_000, 3_000));
+        assert_eq!(
+            amm.quote_withdraw(1_000_000_000).unwrap(),
+            (1_000_000_000, 3_000_000_000)
+        );
+        assert!(amm.quote_withdraw(1_000_000_001).is_err());
+        assert!(amm.quote_withdraw(0).is_err());
+    }
+
+    #[test]
+    fn test_quote_first_deposit() {
+        let amm = test_amm(0, 0, 2500);
+
+        assert_eq!(
+            amm.quote_deposit(4_000, 9_000).unwrap(),
+            DepositQuote {
+                lp_amount: 5_900,
+                amount_0: 4_000,
+                amount_1: 9_000,
+            }
+        );
+        assert!(amm.quote_deposit(10, 10).is_err());
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let amm = test_amm(2_000_000_000, 1_000_000_000, 2500);
+
+        assert_eq!(
+            amm.spot_price(&amm.pool_state.token_0_mint).unwrap(),
+            Decimal::new(5, 1)
+        );
+        assert_eq!(
+            amm.spot_price(&amm.pool_state.token_1_mint).unwrap(),
+            Decimal::from(2)
+        );
+        assert!(amm.spot_price(&Pubkey::new_unique()).is_err());
+
+        let mut amm = amm;
+        amm.vault_0 = VaultState::Missing;
+        assert!(amm.spot_price(&amm.pool_state.token_0_mint).is_err());
+    }
+}
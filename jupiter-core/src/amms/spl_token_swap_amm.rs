@@ -15,6 +15,7 @@ use jupiter_amm_interface::{
 };
 
 use super::account_meta_from_token_swap::TokenSwap;
+use super::amm::ReserveQuery;
 
 mod spl_token_swap_programs {
     use super::*;
@@ -202,3 +203,9 @@ impl Amm for SplTokenSwapAmm {
         Box::new(self.clone())
     }
 }
+
+impl ReserveQuery for SplTokenSwapAmm {
+    fn reserves(&self) -> Result<Vec<u128>> {
+        Ok(self.reserves.to_vec())
+    }
+}
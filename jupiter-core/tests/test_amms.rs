@@ -2,15 +2,25 @@ use std::collections::HashMap;
 
 use ahash::RandomState;
 use anyhow::Error;
-use jupiter_amm_interface::{AmmContext, ClockRef, KeyedAccount, SwapMode, AccountMap};
+use jupiter_amm_interface::{
+    AccountMap, AmmContext, ClockRef, KeyedAccount, QuoteParams, SwapMode,
+};
 use jupiter_core::{
-    amm::Amm,
+    amm::{Amm, ReserveQuery},
     amms::{sega_amm::SegaAmm, spl_token_swap_amm::SplTokenSwapAmm, test_harness::AmmTestHarness},
     route::get_token_mints_permutations,
     test_harness::AmmTestSwapParams,
 };
+use sega_cp_swap::{
+    derive_authority_address, derive_lp_mint_address, derive_observation_address,
+    derive_pool_address, derive_vault_addresses, AMM_CONFIG_SEED,
+};
+use solana_sdk::program_pack::Pack;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::{account::Account, pubkey};
+use spl_token::state::{Account as TokenAccount, AccountState};
+use spl_token_swap::curve::{base::SwapCurve, fees::Fees};
+use spl_token_swap::state::SwapV1;
 
 /// Loads AMM from snapshot and tests quoting
 async fn test_quoting_for_amm_key<T: Amm + 'static>(
@@ -118,6 +128,269 @@ test_exact_in_amms! {
     (SEGA_SOL_SONIC_POOL, SegaAmm, 0),
 }
 
+const SEGA_PROGRAM: Pubkey = pubkey!("SegaXNnoXYTZiqUt9Xn2XqGcL56b25yzXLuJSpadcMu");
+// Config index 0, shared by the mainnet SOL/SONIC pool and the fixture pools
+const SEGA_AMM_CONFIG: Pubkey = pubkey!("BjZxF9CnEuR1vxXffJwun81i3Doe2C2vQCdJpShxCieM");
+// Fixture pools of the same config, see tests/fixtures/README.md
+const SEGA_PLAIN_FIXTURE_POOL: Pubkey = pubkey!("84nf7T6niUC4W9Utd7xG48KTh5iPi1N7SMs9mpdmqpLE");
+const SEGA_TRANSFER_FEE_FIXTURE_POOL: Pubkey =
+    pubkey!("DiWPdE9Coiy5BsNMxni9CC5kpBcWLZ2xYU7fjxW79JVW");
+
+fn load_sega_fixture(pool: Pubkey) -> SegaAmm {
+    let test_harness = AmmTestHarness::new_with_rpc_url("".into(), pool, None);
+    let keyed_account = test_harness.get_keyed_account_from_snapshot().unwrap();
+    let amm_context = AmmContext {
+        clock_ref: ClockRef::from(test_harness.get_clock()),
+    };
+    let mut amm = SegaAmm::from_keyed_account(&keyed_account, &amm_context).unwrap();
+    test_harness.update_amm_from_snapshot(&mut amm).unwrap();
+    amm
+}
+
+/// Exact in quotes of `amount` of `input_mint`, as (in_amount, out_amount, fee_amount)
+fn sega_exact_in(amm: &SegaAmm, input_mint: Pubkey, amount: u64) -> (u64, u64, u64) {
+    let output_mint = *amm
+        .get_reserve_mints()
+        .iter()
+        .find(|mint| **mint != input_mint)
+        .unwrap();
+    let quote = amm
+        .quote(&QuoteParams {
+            amount,
+            input_mint,
+            output_mint,
+            swap_mode: SwapMode::ExactIn,
+        })
+        .unwrap();
+    (quote.in_amount, quote.out_amount, quote.fee_amount)
+}
+
+/// Decodes the fixture pool of two classic mints and quotes both directions
+#[test]
+fn test_quote_sega_plain_mints_fixture() {
+    let amm = load_sega_fixture(SEGA_PLAIN_FIXTURE_POOL);
+    let token_0_mint = pubkey!("GWJT9YncYgv6kb7PvPRKzNwXgULFpy18rVjyvVywdHQ");
+    let token_1_mint = pubkey!("7pdmCq1Vo1haW3xRnWg1cgqDpj6sfRVsnpg2nq9SQwTP");
+
+    let pool_state = amm.pool_state();
+    assert_eq!(pool_state.amm_config, SEGA_AMM_CONFIG);
+    assert_eq!(
+        [pool_state.token_0_mint, pool_state.token_1_mint],
+        [token_0_mint, token_1_mint]
+    );
+    assert_eq!(
+        [pool_state.token_0_vault, pool_state.token_1_vault],
+        [
+            pubkey!("DKBZWX2gqPUi8djGBDbcJ2RjHwpBykmCAuPgDDTGuNaS"),
+            pubkey!("2kvZsBuykcg22DkPmp1URKPrUr32N2S5nbMrbk6XnhLC")
+        ]
+    );
+    assert_eq!(
+        pool_state.observation_key,
+        pubkey!("CJNBkTAzkMdxGaYLXQZUmzqABfdmamMjdNWSjMnfRCbY")
+    );
+    assert_eq!(
+        (pool_state.mint_0_decimals, pool_state.mint_1_decimals),
+        (6, 9)
+    );
+    assert_eq!(pool_state.lp_supply, 10_000_000_000_000);
+    assert_eq!(pool_state.open_time, 1_740_000_000);
+    assert_eq!(amm.fee_rates().unwrap(), (2500, 120_000, 40_000));
+    assert_eq!(
+        amm.token_programs().unwrap(),
+        (spl_token::ID, spl_token::ID)
+    );
+    // The vaults net of the accrued protocol and fund fees
+    assert_eq!(
+        amm.tradable_reserves().unwrap(),
+        (87_500_000_000, 1_250_000_000_000)
+    );
+    assert_eq!(amm.last_observation_timestamp(), Some(1_743_099_900));
+
+    assert_eq!(
+        sega_exact_in(&amm, token_0_mint, 50_000_000),
+        (50_000_000, 712_094_106, 125_000)
+    );
+    assert_eq!(
+        sega_exact_in(&amm, token_1_mint, 1_000_000_000),
+        (1_000_000_000, 69_769_324, 2_500_000)
+    );
+}
+
+/// Decodes the fixture pool of a classic mint and a Token-2022 mint charging a 1% transfer fee,
+/// and quotes both directions
+#[test]
+fn test_quote_sega_transfer_fee_fixture() {
+    let amm = load_sega_fixture(SEGA_TRANSFER_FEE_FIXTURE_POOL);
+    let token_0_mint = pubkey!("3gTEEM6F3BKz4EffyQqFsonTVsEq5YA66F4os6JFy2G5");
+    let token_1_mint = pubkey!("9jW2stn6tfHao4W73EjjAjcPrRLebV1eQzKh1HBeRysY");
+
+    let pool_state = amm.pool_state();
+    assert_eq!(pool_state.amm_config, SEGA_AMM_CONFIG);
+    assert_eq!(
+        [pool_state.token_0_mint, pool_state.token_1_mint],
+        [token_0_mint, token_1_mint]
+    );
+    assert_eq!(
+        [pool_state.token_0_vault, pool_state.token_1_vault],
+        [
+            pubkey!("3B5ViFFLsFtpz54nEhV2YQo9VbHhNwnCUZuA661McyTU"),
+            pubkey!("4UGCDySBM18GUUJT1e3PHSohFojfgxWuQ9pfxFbXViCP")
+        ]
+    );
+    assert_eq!(
+        (pool_state.mint_0_decimals, pool_state.mint_1_decimals),
+        (9, 6)
+    );
+    assert_eq!(pool_state.lp_supply, 4_500_000_000_000);
+    assert_eq!(amm.fee_rates().unwrap(), (2500, 120_000, 40_000));
+    assert_eq!(
+        amm.token_programs().unwrap(),
+        (spl_token::ID, spl_token_2022::ID)
+    );
+    assert_eq!(
+        amm.tradable_reserves().unwrap(),
+        (640_000_000_000, 32_000_000_000)
+    );
+    assert_eq!(amm.last_observation_timestamp(), Some(1_743_099_900));
+
+    // The curve pays out 49_797_386, the mint keeps 1% of it
+    assert_eq!(
+        sega_exact_in(&amm, token_0_mint, 1_000_000_000),
+        (1_000_000_000, 49_299_412, 2_500_000)
+    );
+    // The mint keeps 1% of the input, 49_500_000 reaches the vault
+    assert_eq!(
+        sega_exact_in(&amm, token_1_mint, 50_000_000),
+        (49_500_000, 986_003_588, 123_750)
+    );
+}
+
+/// The mainnet SOL/SONIC pool and the accounts it references sit at the addresses the program
+/// derives from config index 0 and the two mints
+#[test]
+fn test_sega_canonical_addresses() {
+    let sol_mint = pubkey!("So11111111111111111111111111111111111111112");
+    let sonic_mint = pubkey!("SonicxvLud67EceaEzCLRnMTBqzYUUYNr93DBkBdDES");
+
+    assert_eq!(
+        Pubkey::find_program_address(
+            &[AMM_CONFIG_SEED.as_bytes(), &0u16.to_be_bytes()],
+            &SEGA_PROGRAM
+        )
+        .0,
+        SEGA_AMM_CONFIG
+    );
+    assert_eq!(
+        derive_pool_address(&SEGA_PROGRAM, &SEGA_AMM_CONFIG, &sol_mint, &sonic_mint),
+        SEGA_SOL_SONIC_POOL
+    );
+    assert_eq!(
+        derive_vault_addresses(&SEGA_PROGRAM, &SEGA_SOL_SONIC_POOL, &sol_mint, &sonic_mint),
+        (
+            pubkey!("74SwLWTzvJALA2GYh3WhoBY12AitB1crfJs6GFjsoPWC"),
+            pubkey!("FF8VXqRpSsrS7SqhNMNbEw6AQxx6HKJ3uD2Evxmt1BUD")
+        )
+    );
+    assert_eq!(
+        derive_lp_mint_address(&SEGA_PROGRAM, &SEGA_SOL_SONIC_POOL),
+        pubkey!("3UhCWhuke9LCb17ikh5GXLitsFzvJ3rwq99FzofAT9mP")
+    );
+    assert_eq!(
+        derive_observation_address(&SEGA_PROGRAM, &SEGA_SOL_SONIC_POOL),
+        pubkey!("FtpneU7pbmn3A9hG2KYjN7cEFd7gdej7gYZvZ4wzTZWU")
+    );
+    assert_eq!(
+        derive_authority_address(&SEGA_PROGRAM),
+        (pubkey!("7PxeEreoLLMewXcGMivzhiWJjgJgA1LcRWBtQEEjwVcr"), 254)
+    );
+}
+
+/// Orca v2 pool holding `reserves` of two new mints, encoded here rather than snapshotted
+fn spl_token_swap_fixture(reserves: [u64; 2]) -> SplTokenSwapAmm {
+    let state = SwapV1 {
+        is_initialized: true,
+        bump_seed: 255,
+        token_program_id: spl_token::id(),
+        token_a: Pubkey::new_unique(),
+        token_b: Pubkey::new_unique(),
+        pool_mint: Pubkey::new_unique(),
+        token_a_mint: Pubkey::new_unique(),
+        token_b_mint: Pubkey::new_unique(),
+        pool_fee_account: Pubkey::new_unique(),
+        fees: Fees::default(),
+        swap_curve: SwapCurve::default(),
+    };
+    let vaults = [
+        (state.token_a, state.token_a_mint, reserves[0]),
+        (state.token_b, state.token_b_mint, reserves[1]),
+    ];
+    // Preceded by the version byte
+    let mut data = vec![1; 1 + SwapV1::LEN];
+    SwapV1::pack(state, &mut data[1..]).unwrap();
+    let keyed_account = KeyedAccount {
+        key: Pubkey::new_unique(),
+        account: Account {
+            lamports: 0,
+            data,
+            owner: pubkey!("9W959DqEETiGZocYWCQPaJ6sBmUzgfxXfqGeTEdp3aQP"),
+            executable: false,
+            rent_epoch: 0,
+        },
+        params: None,
+    };
+    let mut amm = SplTokenSwapAmm::from_keyed_account(
+        &keyed_account,
+        &AmmContext {
+            clock_ref: ClockRef::default(),
+        },
+    )
+    .unwrap();
+
+    let account_map: AccountMap = vaults
+        .into_iter()
+        .map(|(vault, mint, amount)| {
+            let mut data = vec![0; TokenAccount::LEN];
+            TokenAccount {
+                mint,
+                amount,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            }
+            .pack_into_slice(&mut data);
+            let account = Account {
+                lamports: 0,
+                data,
+                owner: spl_token::id(),
+                executable: false,
+                rent_epoch: 0,
+            };
+            (vault, account)
+        })
+        .collect();
+    amm.update(&account_map).unwrap();
+    amm
+}
+
+/// Reserves of pools of either AMM read through the trait object, from committed or encoded accounts
+#[test]
+fn test_reserves_without_downcasting() {
+    let amms: [Box<dyn ReserveQuery + Send + Sync>; 2] = [
+        Box::new(spl_token_swap_fixture([2_000_000_000, 3_000_000])),
+        Box::new(load_sega_fixture(SEGA_PLAIN_FIXTURE_POOL)),
+    ];
+    for amm in amms.iter() {
+        let reserves = amm.reserves().unwrap();
+        assert_eq!(reserves.len(), amm.get_reserve_mints().len());
+        assert!(
+            reserves.iter().all(|reserve| *reserve > 0),
+            "{}",
+            amm.label()
+        );
+    }
+    assert_eq!(amms[0].reserves().unwrap(), [2_000_000_000, 3_000_000]);
+}
+
 async fn test_quoting_with_amm(
     test_harness: &AmmTestHarness,
     mut amm: Box<dyn Amm>,
@@ -13,6 +13,37 @@ pub enum RoundDirection {
     Ceiling,
 }
 
+/// The curve pricing a pool's swaps, new curves get a variant here and a branch in the quoting code
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CurveType {
+    /// x * y = k
+    #[default]
+    ConstantProduct,
+}
+
+/// A curve discriminant that no [`CurveType`] is implemented for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedCurve(pub u8);
+
+impl std::fmt::Display for UnsupportedCurve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unsupported curve {}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedCurve {}
+
+impl TryFrom<u8> for CurveType {
+    type Error = UnsupportedCurve;
+
+    fn try_from(curve: u8) -> Result<Self, Self::Error> {
+        match curve {
+            0 => Ok(CurveType::ConstantProduct),
+            _ => Err(UnsupportedCurve(curve)),
+        }
+    }
+}
+
 /// Encodes results of depositing both sides at once
 #[derive(Debug, PartialEq)]
 pub struct TradingTokenResult {
@@ -48,6 +79,7 @@ pub struct CurveCalculator {}
 impl CurveCalculator {
     /// Subtract fees and calculate how much destination token will be provided
     /// given an amount of source token.
+    /// Returns `None` if either reserve is empty.
     pub fn swap_base_input(
         source_amount: u128,
         swap_source_amount: u128,
@@ -56,6 +88,10 @@ impl CurveCalculator {
         protocol_fee_rate: u64,
         fund_fee_rate: u64,
     ) -> Option<SwapResult> {
+        if swap_source_amount == 0 || swap_destination_amount == 0 {
+            return None;
+        }
+
         // debit the fee to calculate the amount swapped
         let trade_fee = Fees::trading_fee(source_amount, trade_fee_rate)?;
         let protocol_fee = Fees::protocol_fee(trade_fee, protocol_fee_rate)?;
@@ -81,6 +117,10 @@ impl CurveCalculator {
         })
     }
 
+    /// Calculate how much source token, fees included, has to be provided
+    /// to receive the given amount of destination token.
+    /// Returns `None` if either reserve is empty, or if the pool cannot provide
+    /// that much destination token.
     pub fn swap_base_output(
         destinsation_amount: u128,
         swap_source_amount: u128,
@@ -89,14 +129,17 @@ impl CurveCalculator {
         protocol_fee_rate: u64,
         fund_fee_rate: u64,
     ) -> Option<SwapResult> {
+        if swap_source_amount == 0 || destinsation_amount >= swap_destination_amount {
+            return None;
+        }
+
         let source_amount_swapped = ConstantProductCurve::swap_base_output_without_fees(
             destinsation_amount,
             swap_source_amount,
             swap_destination_amount,
         );
 
-        let source_amount =
-            Fees::calculate_pre_fee_amount(source_amount_swapped, trade_fee_rate).unwrap();
+        let source_amount = Fees::calculate_pre_fee_amount(source_amount_swapped, trade_fee_rate)?;
         let trade_fee = Fees::trading_fee(source_amount, trade_fee_rate)?;
         let protocol_fee = Fees::protocol_fee(trade_fee, protocol_fee_rate)?;
         let fund_fee = Fees::fund_fee(trade_fee, fund_fee_rate)?;
@@ -131,3 +174,130 @@ impl CurveCalculator {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FEE_RATE_DENOMINATOR_VALUE;
+    use proptest::prelude::*;
+
+    fn swap_base_input(
+        source_amount: u64,
+        swap_source_amount: u64,
+        swap_destination_amount: u64,
+        trade_fee_rate: u64,
+    ) -> SwapResult {
+        CurveCalculator::swap_base_input(
+            u128::from(source_amount),
+            u128::from(swap_source_amount),
+            u128::from(swap_destination_amount),
+            trade_fee_rate,
+            120_000,
+            40_000,
+        )
+        .unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn swap_base_input_is_monotonic(
+            source_amount in 1..u64::MAX / 2,
+            extra_source_amount in 0..u64::MAX / 2,
+            swap_source_amount in 1..=u64::MAX,
+            swap_destination_amount in 1..=u64::MAX,
+            trade_fee_rate in 0..FEE_RATE_DENOMINATOR_VALUE,
+        ) {
+            let smaller = swap_base_input(source_amount, swap_source_amount, swap_destination_amount, trade_fee_rate);
+            let larger = swap_base_input(
+                source_amount + extra_source_amount,
+                swap_source_amount,
+                swap_destination_amount,
+                trade_fee_rate,
+            );
+
+            prop_assert!(larger.destination_amount_swapped >= smaller.destination_amount_swapped);
+        }
+
+        #[test]
+        fn swap_base_input_stays_within_reserve(
+            source_amount in 1..=u64::MAX,
+            swap_source_amount in 1..=u64::MAX,
+            swap_destination_amount in 1..=u64::MAX,
+            trade_fee_rate in 0..FEE_RATE_DENOMINATOR_VALUE,
+        ) {
+            let result = swap_base_input(source_amount, swap_source_amount, swap_destination_amount, trade_fee_rate);
+
+            prop_assert!(result.destination_amount_swapped < u128::from(swap_destination_amount));
+            prop_assert_eq!(
+                result.new_swap_destination_amount + result.destination_amount_swapped,
+                u128::from(swap_destination_amount)
+            );
+        }
+
+        #[test]
+        fn swap_base_input_fee_is_less_than_input(
+            source_amount in 1..=u64::MAX,
+            swap_source_amount in 1..=u64::MAX,
+            swap_destination_amount in 1..=u64::MAX,
+            trade_fee_rate in 0..FEE_RATE_DENOMINATOR_VALUE,
+        ) {
+            let result = swap_base_input(source_amount, swap_source_amount, swap_destination_amount, trade_fee_rate);
+
+            // The fee is rounded up, so it only swallows the whole input when that is a handful of tokens
+            let source_amount = u128::from(source_amount);
+            if source_amount * u128::from(FEE_RATE_DENOMINATOR_VALUE - trade_fee_rate)
+                >= u128::from(FEE_RATE_DENOMINATOR_VALUE)
+            {
+                prop_assert!(result.trade_fee < source_amount);
+            } else {
+                prop_assert_eq!(result.trade_fee, source_amount);
+            }
+            prop_assert!(result.protocol_fee + result.fund_fee <= result.trade_fee);
+        }
+    }
+
+    #[test]
+    fn swap_base_input_dust_is_all_fee() {
+        // A single token at a 0.25% fee rate rounds the fee up to the whole input
+        let result = swap_base_input(1, 1_000_000, 1_000_000, 2500);
+
+        assert_eq!(result.trade_fee, 1);
+        assert_eq!(result.destination_amount_swapped, 0);
+        assert_eq!(result.new_swap_source_amount, 1_000_001);
+        assert_eq!(result.new_swap_destination_amount, 1_000_000);
+    }
+
+    #[test]
+    fn curve_type_from_discriminant() {
+        assert_eq!(CurveType::try_from(0), Ok(CurveType::ConstantProduct));
+        for curve in [1, 2, u8::MAX] {
+            assert_eq!(CurveType::try_from(curve), Err(UnsupportedCurve(curve)));
+        }
+        assert_eq!(UnsupportedCurve(1).to_string(), "Unsupported curve 1");
+    }
+
+    #[test]
+    fn swap_rejects_empty_reserves() {
+        for (source_reserve, destination_reserve) in [(0, 1_000_000), (1_000_000, 0), (0, 0)] {
+            let swap_base_input = CurveCalculator::swap_base_input(
+                1_000,
+                source_reserve,
+                destination_reserve,
+                2500,
+                0,
+                0,
+            );
+            let swap_base_output = CurveCalculator::swap_base_output(
+                1_000,
+                source_reserve,
+                destination_reserve,
+                2500,
+                0,
+                0,
+            );
+
+            assert_eq!(swap_base_input, None);
+            assert_eq!(swap_base_output, None);
+        }
+    }
+}
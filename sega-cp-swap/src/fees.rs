@@ -5,10 +5,20 @@ pub const FEE_RATE_DENOMINATOR_VALUE: u64 = 1_000_000;
 
 pub struct Fees {}
 
+/// Split of the trade fee charged on a swap, denominated in the source token
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeBreakdown {
+    /// Total fee debited from the source amount, includes the protocol and fund fees
+    pub trade_fee: u64,
+    /// Portion of the trade fee owed to the protocol
+    pub protocol_fee: u64,
+    /// Portion of the trade fee owed to the fund
+    pub fund_fee: u64,
+}
+
 fn ceil_div(token_amount: u128, fee_numerator: u128, fee_denominator: u128) -> Option<u128> {
     token_amount
-        .checked_mul(u128::from(fee_numerator))
-        .unwrap()
+        .checked_mul(fee_numerator)?
         .checked_add(fee_denominator)?
         .checked_sub(1)?
         .checked_div(fee_denominator)
@@ -77,3 +87,34 @@ impl Fees {
         Some(trade_fee_pct)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fees_overflow_to_none() {
+        let amount = u128::MAX - 1;
+        assert_eq!(Fees::trading_fee(amount, 2500), None);
+        assert_eq!(Fees::protocol_fee(amount, 120_000), None);
+        assert_eq!(Fees::fund_fee(amount, 40_000), None);
+        assert_eq!(Fees::calculate_pre_fee_amount(amount, 2500), None);
+
+        // A rate of zero leaves nothing to multiply
+        assert_eq!(Fees::protocol_fee(amount, 0), Some(0));
+        assert_eq!(Fees::calculate_pre_fee_amount(amount, 0), Some(amount));
+    }
+
+    #[test]
+    fn pre_fee_amount_rejects_full_trade_fee() {
+        assert_eq!(
+            Fees::calculate_pre_fee_amount(1_000, FEE_RATE_DENOMINATOR_VALUE),
+            None
+        );
+        assert_eq!(Fees::calculate_pre_fee_amount(1_000, u64::MAX), None);
+        assert_eq!(
+            Fees::calculate_pre_fee_amount(997_500, 2500),
+            Some(1_000_000)
+        );
+    }
+}
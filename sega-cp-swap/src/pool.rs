@@ -1,13 +1,76 @@
+use crate::calculator::{CurveType, UnsupportedCurve};
 use anchor_lang::prelude::*;
 use std::ops::BitAnd;
 /// Seed to derive account address and signature
 pub const POOL_SEED: &str = "pool";
 pub const POOL_LP_MINT_SEED: &str = "pool_lp_mint";
 pub const POOL_VAULT_SEED: &str = "pool_vault";
+pub const OBSERVATION_SEED: &str = "observation";
 
 pub const Q32: u128 = (u32::MAX as u128) + 1; // 2^32
 pub const OBSERVATION_NUM: usize = 100;
 
+/// Address of the pool for `amm_config` and the mint pair, `token_0_mint` being the smaller mint
+pub fn derive_pool_address(
+    program_id: &Pubkey,
+    amm_config: &Pubkey,
+    token_0_mint: &Pubkey,
+    token_1_mint: &Pubkey,
+) -> Pubkey {
+    Pubkey::find_program_address(
+        &[
+            POOL_SEED.as_bytes(),
+            amm_config.as_ref(),
+            token_0_mint.as_ref(),
+            token_1_mint.as_ref(),
+        ],
+        program_id,
+    )
+    .0
+}
+
+/// Addresses of the token 0 and token 1 vaults of `pool_state`
+pub fn derive_vault_addresses(
+    program_id: &Pubkey,
+    pool_state: &Pubkey,
+    token_0_mint: &Pubkey,
+    token_1_mint: &Pubkey,
+) -> (Pubkey, Pubkey) {
+    let vault_address = |token_mint: &Pubkey| {
+        Pubkey::find_program_address(
+            &[
+                POOL_VAULT_SEED.as_bytes(),
+                pool_state.as_ref(),
+                token_mint.as_ref(),
+            ],
+            program_id,
+        )
+        .0
+    };
+    (vault_address(token_0_mint), vault_address(token_1_mint))
+}
+
+pub fn derive_lp_mint_address(program_id: &Pubkey, pool_state: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[POOL_LP_MINT_SEED.as_bytes(), pool_state.as_ref()],
+        program_id,
+    )
+    .0
+}
+
+pub fn derive_observation_address(program_id: &Pubkey, pool_state: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[OBSERVATION_SEED.as_bytes(), pool_state.as_ref()],
+        program_id,
+    )
+    .0
+}
+
+/// The authority owning the vaults and the LP mints of every pool, along with its bump
+pub fn derive_authority_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[crate::AUTH_SEED.as_bytes()], program_id)
+}
+
 pub enum PoolStatusBitIndex {
     Deposit,
     Withdraw,
@@ -85,6 +148,12 @@ impl PoolState {
         self.status.bitand(status) == 0
     }
 
+    /// The pool layout does not encode a curve yet, so every Sega pool reads as curve 0, constant product.
+    /// A layout that adds one decodes it here, failing on curves the quote cannot price
+    pub fn curve_type(&self) -> std::result::Result<CurveType, UnsupportedCurve> {
+        CurveType::try_from(0)
+    }
+
     pub fn vault_amount_without_fee(&self, vault_0: u64, vault_1: u64) -> (u64, u64) {
         (
             vault_0
@@ -129,4 +198,43 @@ pub struct ObservationState {
 
 impl ObservationState {
     pub const LEN: usize = 8 + 1 + 2 + 32 + (OBSERVATION_NUM * Observation::LEN) + 4 * 8;
+
+    /// Time weighted average of the token_0 and token_1 prices, Q32.32, from the newest observation
+    /// recorded at or before `now - window_seconds` up to the most recent observation.
+    /// Returns None if the observations do not reach back far enough.
+    pub fn twap_price_x32(&self, now: u64, window_seconds: u64) -> Option<(u128, u128)> {
+        if !self.initialized {
+            return None;
+        }
+        let latest_index = usize::from(self.observation_index);
+        let latest = *self.observations.get(latest_index)?;
+        let target_timestamp = now.checked_sub(window_seconds)?;
+
+        // Walk the ring buffer backwards from the most recent observation, unwritten slots are zeroed
+        let start = (1..OBSERVATION_NUM)
+            .map(|offset| {
+                self.observations[(latest_index + OBSERVATION_NUM - offset) % OBSERVATION_NUM]
+            })
+            .take_while(|observation| observation.block_timestamp != 0)
+            .find(|observation| observation.block_timestamp <= target_timestamp)?;
+
+        let elapsed = latest.block_timestamp.checked_sub(start.block_timestamp)?;
+        if elapsed == 0 {
+            return None;
+        }
+        let (start_token_0_price_x32, start_token_1_price_x32) = (
+            start.cumulative_token_0_price_x32,
+            start.cumulative_token_1_price_x32,
+        );
+        let (latest_token_0_price_x32, latest_token_1_price_x32) = (
+            latest.cumulative_token_0_price_x32,
+            latest.cumulative_token_1_price_x32,
+        );
+
+        // Cumulative prices are allowed to overflow on chain
+        Some((
+            latest_token_0_price_x32.wrapping_sub(start_token_0_price_x32) / u128::from(elapsed),
+            latest_token_1_price_x32.wrapping_sub(start_token_1_price_x32) / u128::from(elapsed),
+        ))
+    }
 }
\ No newline at end of file
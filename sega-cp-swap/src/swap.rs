@@ -1,5 +1,8 @@
 use anchor_lang::prelude::{AccountMeta, Pubkey, ToAccountMetas};
+use anchor_lang::solana_program::hash::hash;
 
+/// Accounts of a Sega swap, in the Raydium CP swap order that `Swap::RaydiumCP` routes with:
+/// the program followed by the 13 accounts of `swap_base_input` / `swap_base_output`
 #[derive(Copy, Clone, Debug)]
 pub struct SegaSwap {
     pub program: Pubkey,
@@ -18,6 +21,36 @@ pub struct SegaSwap {
     pub observation_state: Pubkey,
 }
 
+impl SegaSwap {
+    /// Number of account metas produced by `to_account_metas`
+    pub const ACCOUNTS_LEN: usize = 14;
+    /// Field names of the accounts, in the order of `to_account_metas`
+    pub const ACCOUNT_NAMES: [&'static str; Self::ACCOUNTS_LEN] = [
+        "program",
+        "payer",
+        "authority",
+        "amm_config",
+        "pool_state",
+        "input_token_account",
+        "output_token_account",
+        "input_vault",
+        "output_vault",
+        "input_token_program",
+        "output_token_program",
+        "input_token_mint",
+        "output_token_mint",
+        "observation_state",
+    ];
+
+    /// Instruction data of `swap_base_input`, the Anchor discriminator followed by its arguments
+    pub fn swap_base_input_data(amount_in: u64, minimum_amount_out: u64) -> Vec<u8> {
+        let mut data = hash(b"global:swap_base_input").to_bytes()[..8].to_vec();
+        data.extend_from_slice(&amount_in.to_le_bytes());
+        data.extend_from_slice(&minimum_amount_out.to_le_bytes());
+        data
+    }
+}
+
 impl ToAccountMetas for SegaSwap {
     fn to_account_metas(&self, _is_signer: Option<bool>) -> Vec<AccountMeta> {
         vec![